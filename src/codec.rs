@@ -0,0 +1,334 @@
+use common::helpers::Description;
+use consts::common::FRAME_HEADER_SIZE;
+use rmp::Marker;
+use rmp::decode as msgpack_decode;
+use rmp::encode as msgpack_encode;
+use std::io::{self, Read};
+
+// === data ===
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum FramingEnum {
+  // 4 bytes of body size (big-endian) and body
+  Length,
+  // one json record in line (for nc, socat and other simple tools)
+  Lines,
+}
+
+// encoding of command and answer records in frame body
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum EncodingEnum {
+  Json,
+  MsgPack,
+}
+
+pub enum FrameError {
+  TooLarge(usize),
+}
+
+// field value of MessagePack record (map with string keys)
+#[derive(PartialEq, Debug)]
+pub enum MsgPackValue {
+  Nil,
+  Bool(bool),
+  Uint(u64),
+  Str(String),
+  Bin(Vec<u8>),
+}
+
+// Read buffer of one client connection.
+pub struct FrameBuffer {
+  framing: FramingEnum,
+  chunk: Vec<u8>,
+  data: Vec<u8>,
+  max_frame_size: usize,
+}
+
+// === impl ===
+impl FramingEnum {
+  pub fn from_name(name: &str) -> Option<Self> {
+    match name {
+      "length" => Some(FramingEnum::Length),
+      "lines" => Some(FramingEnum::Lines),
+      _ => None,
+    }
+  }
+}
+
+impl EncodingEnum {
+  pub fn from_name(name: &str) -> Option<Self> {
+    match name {
+      "json" => Some(EncodingEnum::Json),
+      "msgpack" => Some(EncodingEnum::MsgPack),
+      _ => None,
+    }
+  }
+
+  pub fn name(&self) -> String {
+    match *self {
+      EncodingEnum::Json => "json",
+      EncodingEnum::MsgPack => "msgpack",
+    }.to_string()
+  }
+}
+
+impl FrameBuffer {
+  pub fn new(framing: FramingEnum, chunk_size: usize, max_frame_size: usize) -> Self {
+    FrameBuffer {
+      framing: framing,
+      chunk: vec![0u8; chunk_size],
+      data: Vec::with_capacity(chunk_size),
+      max_frame_size: max_frame_size,
+    }
+  }
+
+  // read next part of data from stream, returns size of part
+  pub fn fill(&mut self, stream: &mut Read) -> io::Result<usize> {
+    let size = try!(stream.read(&mut self.chunk));
+    self.data.extend_from_slice(&self.chunk[..size]);
+    Ok(size)
+  }
+
+  // take next complete frame body if it has already been received
+  pub fn next_frame(&mut self) -> Result<Option<Vec<u8>>, FrameError> {
+    match self.framing {
+      FramingEnum::Length => self.next_length_frame(),
+      FramingEnum::Lines => self.next_line_frame(),
+    }
+  }
+
+  fn next_length_frame(&mut self) -> Result<Option<Vec<u8>>, FrameError> {
+    if self.data.len() < FRAME_HEADER_SIZE {
+      return Ok(None);
+    }
+    let mut size: usize = 0;
+    for index in 0..FRAME_HEADER_SIZE {
+      size = (size << 8) | self.data[index] as usize;
+    }
+    if size > self.max_frame_size {
+      return Err(FrameError::TooLarge(size));
+    }
+    let end = FRAME_HEADER_SIZE + size;
+    if self.data.len() < end {
+      // wait the rest of frame
+      return Ok(None);
+    }
+    let frame: Vec<u8> = self.data[FRAME_HEADER_SIZE..end].to_vec();
+    self.data.drain(..end);
+    Ok(Some(frame))
+  }
+
+  fn next_line_frame(&mut self) -> Result<Option<Vec<u8>>, FrameError> {
+    loop {
+      let end = match self.data.iter().position(|ch| *ch == b'\n') {
+        Some(end) => end,
+        None => {
+          // partial line stays in buffer
+          return if self.data.len() > self.max_frame_size {
+            Err(FrameError::TooLarge(self.data.len()))
+          } else {
+            Ok(None)
+          };
+        }
+      };
+      if end > self.max_frame_size {
+        return Err(FrameError::TooLarge(end));
+      }
+      let mut frame: Vec<u8> = self.data[..end].to_vec();
+      self.data.drain(..end + 1);
+      if frame.last() == Some(&b'\r') {
+        frame.pop();
+      }
+      if !frame.is_empty() {
+        return Ok(Some(frame));
+      }
+    }
+  }
+
+  pub fn pending(&self) -> usize {
+    self.data.len()
+  }
+}
+
+pub fn encode_frame(body: &[u8], framing: &FramingEnum) -> Vec<u8> {
+  if *framing == FramingEnum::Lines {
+    let mut line: Vec<u8> = Vec::with_capacity(body.len() + 1);
+    line.extend_from_slice(body);
+    line.push(b'\n');
+    return line;
+  }
+  let size = body.len();
+  let mut frame: Vec<u8> = Vec::with_capacity(FRAME_HEADER_SIZE + size);
+  for index in 0..FRAME_HEADER_SIZE {
+    frame.push((size >> (8 * (FRAME_HEADER_SIZE - index - 1))) as u8);
+  }
+  frame.extend_from_slice(body);
+  frame
+}
+
+fn read_msgpack_value(data: &mut &[u8]) -> Option<MsgPackValue> {
+  let marker = match data.first() {
+    Some(byte) => Marker::from_u8(*byte),
+    None => return None,
+  };
+  let (size, text) = match marker {
+    Marker::Null => {
+      return msgpack_decode::read_nil(data).ok().map(|_| MsgPackValue::Nil);
+    },
+    Marker::True | Marker::False => {
+      return msgpack_decode::read_bool(data).ok().map(|value| MsgPackValue::Bool(value));
+    },
+    Marker::FixPos(_) | Marker::U8 | Marker::U16 | Marker::U32 | Marker::U64 => {
+      return msgpack_decode::read_int(data).ok().map(|value| MsgPackValue::Uint(value));
+    },
+    Marker::FixStr(_) | Marker::Str8 | Marker::Str16 | Marker::Str32 => {
+      (msgpack_decode::read_str_len(data).ok(), true)
+    },
+    // binary payload is kept as is
+    Marker::Bin8 | Marker::Bin16 | Marker::Bin32 => {
+      (msgpack_decode::read_bin_len(data).ok(), false)
+    },
+    _ => (None, false),
+  };
+  match size {
+    Some(size) if size as usize <= data.len() => {
+      let (value, rest) = data.split_at(size as usize);
+      *data = rest;
+      if text {
+        String::from_utf8(value.to_vec()).ok().map(|line| MsgPackValue::Str(line))
+      } else {
+        Some(MsgPackValue::Bin(value.to_vec()))
+      }
+    },
+    _ => None,
+  }
+}
+
+// fields of MessagePack map record
+pub fn decode_msgpack_record(body: &[u8]) -> Option<Vec<(String, MsgPackValue)>> {
+  let mut data: &[u8] = body;
+  let size = match msgpack_decode::read_map_len(&mut data) {
+    Ok(size) => size,
+    Err(_) => return None,
+  };
+  let mut fields: Vec<(String, MsgPackValue)> = Vec::new();
+  for _ in 0..size {
+    let name = match read_msgpack_value(&mut data) {
+      Some(MsgPackValue::Str(name)) => name,
+      _ => return None,
+    };
+    match read_msgpack_value(&mut data) {
+      Some(value) => fields.push((name, value)),
+      None => return None,
+    }
+  }
+  if data.is_empty() {
+    Some(fields)
+  } else {
+    None
+  }
+}
+
+pub fn encode_msgpack_record(fields: &Vec<(&str, MsgPackValue)>) -> Vec<u8> {
+  let mut body: Vec<u8> = Vec::new();
+  // write to memory can't fail
+  msgpack_encode::write_map_len(&mut body, fields.len() as u32).unwrap();
+  for &(name, ref value) in fields.iter() {
+    msgpack_encode::write_str(&mut body, name).unwrap();
+    match *value {
+      MsgPackValue::Nil => msgpack_encode::write_nil(&mut body).unwrap(),
+      MsgPackValue::Bool(value) => msgpack_encode::write_bool(&mut body, value).unwrap(),
+      MsgPackValue::Uint(value) => {
+        msgpack_encode::write_uint(&mut body, value).unwrap();
+      },
+      MsgPackValue::Str(ref value) => msgpack_encode::write_str(&mut body, value).unwrap(),
+      MsgPackValue::Bin(ref value) => msgpack_encode::write_bin(&mut body, value).unwrap(),
+    }
+  }
+  body
+}
+
+// === impl trait ===
+impl Description for FrameError {
+  fn description(&self) -> String {
+    match *self {
+      FrameError::TooLarge(size) => format!("frame size {} is too large", size),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use codec::{
+    FrameBuffer, FramingEnum, MsgPackValue, encode_frame, encode_msgpack_record,
+    decode_msgpack_record};
+  use std::io::Cursor;
+
+  #[test]
+  fn test_frame_split_and_glued() {
+    let framing = FramingEnum::Length;
+    let mut content = encode_frame("{\"a\": 1}".as_bytes(), &framing);
+    content.extend(encode_frame("{\"b\": 2}".as_bytes(), &framing));
+    let mut stream = Cursor::new(content);
+    // small chunk, frames come by parts
+    let mut buffer = FrameBuffer::new(framing, 5, 1024);
+    let mut frames: Vec<String> = Vec::new();
+    loop {
+      match buffer.next_frame() {
+        Ok(Some(frame)) => frames.push(String::from_utf8(frame).unwrap()),
+        Ok(None) => {
+          if buffer.fill(&mut stream).unwrap() == 0 {
+            break;
+          }
+        },
+        Err(_) => panic!("Frame error!"),
+      }
+    }
+    assert_eq!(frames, vec!["{\"a\": 1}".to_string(), "{\"b\": 2}".to_string()]);
+    assert_eq!(buffer.pending(), 0);
+  }
+
+  #[test]
+  fn test_frame_too_large() {
+    let mut stream = Cursor::new(encode_frame(&vec![32u8; 100], &FramingEnum::Length));
+    let mut buffer = FrameBuffer::new(FramingEnum::Length, 64, 10);
+    buffer.fill(&mut stream).unwrap();
+    assert!(buffer.next_frame().is_err());
+  }
+
+  #[test]
+  fn test_line_frames() {
+    let mut stream = Cursor::new("{\"a\": 1}\r\n\n{\"b\":".as_bytes().to_vec());
+    let mut buffer = FrameBuffer::new(FramingEnum::Lines, 4, 16);
+    while buffer.fill(&mut stream).unwrap() > 0 {}
+    assert_eq!(buffer.next_frame().ok().unwrap(), Some("{\"a\": 1}".as_bytes().to_vec()));
+    // partial line wait next read
+    assert_eq!(buffer.next_frame().ok().unwrap(), None);
+    assert_eq!(buffer.pending(), 5);
+
+    let mut long_stream = Cursor::new(vec![32u8; 20]);
+    buffer.fill(&mut long_stream).unwrap();
+    buffer.fill(&mut long_stream).unwrap();
+    buffer.fill(&mut long_stream).unwrap();
+    assert!(buffer.next_frame().is_err());
+  }
+
+  #[test]
+  fn test_msgpack_record() {
+    let body = encode_msgpack_record(&vec![
+      ("target", MsgPackValue::Uint(2)),
+      ("part", MsgPackValue::Bool(false)),
+      ("data", MsgPackValue::Str("hello".to_string())),
+      ("rid", MsgPackValue::Nil)]);
+    let fields = decode_msgpack_record(&body).unwrap();
+    assert_eq!(fields.len(), 4);
+    assert_eq!(fields[0], ("target".to_string(), MsgPackValue::Uint(2)));
+    assert_eq!(fields[2], ("data".to_string(), MsgPackValue::Str("hello".to_string())));
+    // broken record
+    assert!(decode_msgpack_record(&body[..body.len() - 2]).is_none());
+
+    let blob = vec![0u8, 159, 146, 150, 255];
+    let body = encode_msgpack_record(&vec![("blob", MsgPackValue::Bin(blob.clone()))]);
+    let fields = decode_msgpack_record(&body).unwrap();
+    assert_eq!(fields[0], ("blob".to_string(), MsgPackValue::Bin(blob)));
+  }
+}
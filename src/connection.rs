@@ -1,10 +1,16 @@
+extern crate time;
 
+use codec::{FrameBuffer, FramingEnum, EncodingEnum};
 use common::helpers::Description;
 use consts::common::{
-  STD_LOOP_DELAY, NOTARGET_DELAY, MIN_BUFFER_SIZE, CONNECTION_FINISH_TIMEOUT};
+  STD_LOOP_DELAY, NOTARGET_DELAY, MIN_BUFFER_SIZE, CONNECTION_FINISH_TIMEOUT,
+  TLS_HANDSHAKE_TIMEOUT};
+use consts::messages::{
+  FRAME_ERROR_TMP, NOT_AUTHORIZED_TMP, HEARTBEAT_TIMEOUT_TMP, PART_ERROR_TMP};
+use openssl::ssl::SslAcceptor;
 use options::configuration::ProjectOptions;
 use protocol::{
-  TargetAsDigit, AnswerTargetEnum, LookAsTargetAnswerEnum};
+  TargetAsDigit, AnswerTargetEnum, LookAsTargetAnswerEnum, ErrorCodeEnum};
 use rustc_serialize::json;
 use std::clone::Clone;
 use std::collections::HashSet;
@@ -12,14 +18,24 @@ use std::error::Error;
 use std::net::{SocketAddr, TcpListener};
 use std::sync::{Arc, Mutex};
 use std::thread;
-use std::io::prelude::*;
+use std::time::Duration;
+use std::io::{ErrorKind, Write};
 use transport::{
   Answer, Command, ClientIdConstructor, TransportConstructor, JsonBufferCommand,
   CommandDataCreator, LockManager, AnswerWriter, ClientConnectionData,
-  CuidOwner, CuidSource};
+  CuidOwner, CuidSource, PartError};
+use tls::{ClientStream, create_acceptor, accept_client};
 
 
-fn get_buffer_command_record(data: Vec<u8>, label: &String) -> Option<JsonBufferCommand> {
+fn get_buffer_command_record(
+    data: Vec<u8>, encoding: &EncodingEnum, label: &String) -> Option<JsonBufferCommand> {
+  if *encoding == EncodingEnum::MsgPack {
+    let record = JsonBufferCommand::from_msgpack(&data);
+    if record.is_none() {
+      warn!("MessagePack data format error from client {}", label);
+    }
+    return record;
+  }
   let result: Option<JsonBufferCommand> = match String::from_utf8(data) {
     Ok(line) => match(json::decode(&line)) {
       Ok(record) => Some(record),
@@ -41,27 +57,26 @@ fn prepare_command(
     buffer: &JsonBufferCommand,
     addr: &SocketAddr,
     options: &ProjectOptions,
-    create_cuid: bool) -> bool {
+    create_cuid: bool) -> Result<bool, PartError> {
   //
+  let max_size = options.max_command_size as usize;
   if command.is_new() {
     // new command income
-    command.setup(&buffer);
+    try!(command.setup(&buffer, max_size));
     if create_cuid {
       command.create_cuid(&addr, &options);
     }
   } else {
     // append data to buffer command
-    command.append(&buffer);
+    try!(command.append(&buffer, max_size));
   }
-  command.is_full()
+  Ok(command.is_full())
 }
 
 fn send_command_to_workers(
     buffer_size: usize,
     new_command: &Command,
-    current_connection_data: &ClientConnectionData,
     command_pool: &Mutex<Vec<Command>>,
-    connection_data_pool: &Mutex<Vec<ClientConnectionData>>,
     wait: bool,
     label: &String) -> usize {
   //
@@ -69,7 +84,8 @@ fn send_command_to_workers(
     Ok(mut local_command_pool) => {
       let mut index = 0;
       for command in local_command_pool.iter_mut() {
-        if command.lock() {
+        // cell with not executed command is full
+        if !command.is_full() && command.lock() {
           command.copy(&new_command);
           command.unlock();
           break;
@@ -83,38 +99,13 @@ fn send_command_to_workers(
       buffer_size.clone()
     }
   };
-  // set client client connection data
-  // for worker iteration
-  if buffer_index < buffer_size {
-    // temp data pool set
-    loop {
-      let moved = match connection_data_pool.lock() {
-        Ok(mut local_connection_data_pool) => {
-          local_connection_data_pool[buffer_index].clear();
-          local_connection_data_pool[buffer_index].copy(&current_connection_data);
-          true
-        },
-        Err(err) => {
-          error!("Temp data pool busy for cell {}, error: {}", buffer_index, err);
-          false
-        }
-      };
-      if moved {
-        break
-      } else {
-        thread::sleep_ms(STD_LOOP_DELAY);
-      }
-    }
-  }
   let result = if buffer_index >= buffer_size && wait {
     thread::sleep_ms(STD_LOOP_DELAY);
     // recursively after pause
     send_command_to_workers(
       buffer_size,
       &new_command,
-      &current_connection_data,
       &command_pool,
-      &connection_data_pool,
       true,
       &label)
   } else {
@@ -123,284 +114,476 @@ fn send_command_to_workers(
   result
 }
 
-fn wait_answer(
-    buffer_index: usize,
-    cuid: String,
+fn write_error(
+    stream: &mut Write,
+    code: ErrorCodeEnum,
+    message: String,
+    connection_data: &ClientConnectionData,
+    framing: &FramingEnum,
+    encoding: &EncodingEnum) -> bool {
+  // error from connection, not from worker
+  let mut answer = Answer::new();
+  answer.set_target(AnswerTargetEnum::Error.to_u32());
+  answer.set_data(code.answer_data(&message, connection_data.get_version()));
+  answer.complete(connection_data.get_cuid());
+  answer.write(stream, framing, encoding)
+}
+
+fn take_answers(
+    cuid: &String,
     answer_pool: &Mutex<Vec<Answer>>,
-    current_connection_data: &mut ClientConnectionData,
-    connection_data_pool: &Mutex<Vec<ClientConnectionData>>,
-    wait: bool,
-    label: &String) -> Option<Answer> {
-  //
-  let mut this_answer: Option<Answer> = None;
-  let has_answer: bool = match answer_pool.lock() {
+    label: &String) -> Vec<Answer> {
+  // all ready answers for client, in any order
+  let mut answers: Vec<Answer> = Vec::new();
+  match answer_pool.lock() {
     Ok(mut local_answer_pool) => {
-      let mut uid_equal = false;
-      if local_answer_pool[buffer_index].lock() {
-        let cell_uid = local_answer_pool[buffer_index].get_cuid();
-        if cell_uid == cuid {
-          if !local_answer_pool[buffer_index].is_shipped() {
-            uid_equal = true;
-            this_answer = Some(local_answer_pool[buffer_index].clone());
-            // buffer cell free
-            local_answer_pool[buffer_index].clear();
-            debug!("Answer cell {} from worker go to client {}", buffer_index, cuid);
-          } else {
-            error!(
-              "Answer cell {} cuid {} must send = false! From client: {}",
-              buffer_index, cuid, label);
-          }
-        } else {
-          warn!(
-            "Answer cell {} wait {} exists {} from client: {}",
-            buffer_index, cuid, cell_uid, label);
+      let mut index = 0;
+      for answer_cell in local_answer_pool.iter_mut() {
+        if !answer_cell.is_empty() && answer_cell.get_cuid() == *cuid && answer_cell.lock() {
+          answers.push(answer_cell.clone());
+          // buffer cell free
+          answer_cell.clear();
+          debug!("Answer cell {} from worker go to client {}", index, cuid);
         }
-        local_answer_pool[buffer_index].unlock();
+        index += 1;
       }
-      uid_equal
     },
     Err(err) => {
       warn!(
-        "Commad {} can't get pool for answer for client: {} error: {}",
-        cuid, label, err);
-      false
+        "Can't get pool for answer for client: {} error: {}",
+        label, err);
     },
   };
-  let mut moved = !has_answer;
-  while !moved {
-    moved = match connection_data_pool.lock() {
-      Ok(mut local_connection_data_pool) => {
-        current_connection_data.clear();
-        current_connection_data.copy(&local_connection_data_pool[buffer_index]);
-        local_connection_data_pool[buffer_index].clear();
+  answers
+}
+
+// client certificate replaces SigIn and Auth
+fn certificate_login(
+    connection_data: &mut ClientConnectionData,
+    identity: Option<String>,
+    options: &ProjectOptions,
+    label: &String) -> bool {
+  match identity {
+    Some(identity) => match options.get_credentials(&identity) {
+      Some(credentials) => {
+        info!("Client {} has certificate of '{}'", label, identity);
+        connection_data.set_identity(identity.clone());
+        connection_data.set_group(credentials.group);
+        connection_data.set_auth(true);
         true
       },
-      Err(err) => {
-        error!(
-          "Temp data pool busy for cell {}, error: {}",
-          buffer_index,
-          err);
+      None => {
+        warn!("Client {} has certificate of unknown '{}'", label, identity);
         false
-      },
-    };
-    if !moved {
-      thread::sleep_ms(STD_LOOP_DELAY);
+      }
+    },
+    None => false,
+  }
+}
+
+fn client_loop(
+    mut stream: ClientStream,
+    local_options: ProjectOptions,
+    buffer_size: usize,
+    arc_local_command_pool: Arc<Mutex<Vec<Command>>>,
+    arc_local_answer_pool: Arc<Mutex<Vec<Answer>>>,
+    arc_local_closed_clients_set: Arc<Mutex<HashSet<String>>>) {
+  let client_addr = match stream.peer_addr() {
+    Ok(addr) => addr,
+    Err(err) => {
+      warn!("Connection without address: {}", err);
+      return;
+    }
+  };
+  let client_socket_label= format!("{}", client_addr);
+  let command_buffer_size = local_options.command_buffer as usize;
+  let pipeline_size = local_options.pipeline_size as usize;
+  let framing = local_options.framing;
+  let mut encoding = EncodingEnum::Json;
+  let mut frame_buffer = FrameBuffer::new(
+    framing, buffer_size, local_options.max_frame_size as usize);
+  let mut close = false;
+  let mut buffer_command = Command::new();
+  let mut last_cuid: Option<String> = None;
+  let mut connection_data: ClientConnectionData = ClientConnectionData::new();
+  // commands in workers without answer
+  let mut in_flight: usize = 0;
+  // command changes connection data, next commands wait it
+  let mut exclusive = false;
+  // full command waits place in pipeline or in workers buffer
+  let mut hold = false;
+  // time of last data from client (sec)
+  let mut last_activity = time::get_time().sec;
+  // read don't block answers sending
+  match stream.set_read_timeout(Some(Duration::from_millis(STD_LOOP_DELAY as u64))) {
+    Ok(_) => {},
+    Err(err) => {
+      close = true;
+      error!("Can't set read timeout for {}: {}", client_socket_label, err);
     }
   }
-  let result = if has_answer {
-    this_answer
-  } else {
-    if wait {
+  connection_data.set_address(format!("{}", client_addr.ip()));
+  let mut auth = certificate_login(
+    &mut connection_data, stream.peer_identity(), &local_options, &client_socket_label);
+  while !close {
+    let reading = !hold && !exclusive;
+    if reading {
+      match frame_buffer.fill(&mut stream) {
+        Ok(size) => {
+          close = size == 0;
+          last_activity = time::get_time().sec;
+        },
+        Err(ref err) if err.kind() == ErrorKind::WouldBlock ||
+                        err.kind() == ErrorKind::TimedOut => {
+          // no data yet, client must send something in each heartbeat interval
+          let heartbeat = if connection_data.get_heartbeat() > 0 {
+            connection_data.get_heartbeat()
+          } else {
+            local_options.heartbeat_interval
+          };
+          let idle_limit = (heartbeat * local_options.heartbeat_misses) as i64;
+          if time::get_time().sec - last_activity > idle_limit {
+            close = true;
+            warn!(
+              "Connection {} missed {} heartbeats ({} sec)",
+              client_socket_label, local_options.heartbeat_misses, heartbeat);
+            write_error(
+              &mut stream,
+              ErrorCodeEnum::HeartbeatTimeout,
+              HEARTBEAT_TIMEOUT_TMP.to_string(),
+              &connection_data,
+              &framing,
+              &encoding);
+          }
+        },
+        Err(err) => {
+          close = true;
+          warn!("connection {} close with error {}", client_socket_label, err);
+        }
+      }
+    }
+    while !close && !exclusive {
+      if !hold {
+        let frame = match frame_buffer.next_frame() {
+          Ok(Some(frame)) => frame,
+          Ok(None) => break,
+          Err(err) => {
+            warn!(
+              "Frame error '{}' from client {}",
+              err.description(), client_socket_label);
+            write_error(
+              &mut stream,
+              ErrorCodeEnum::MalformedFrame,
+              format!("{} {}", FRAME_ERROR_TMP, err.description()),
+              &connection_data,
+              &framing,
+              &encoding);
+            close = true;
+            break;
+          }
+        };
+        let new_json_command = get_buffer_command_record(
+          frame, &encoding, &client_socket_label);
+
+        let done = match new_json_command {
+          Some(json_buffer) => {
+            let need_cuid = !connection_data.has_cuid();
+            let parce_done = prepare_command(
+              &mut buffer_command,
+              &json_buffer,
+              &client_addr,
+              &local_options,
+              need_cuid);
+
+            if need_cuid {
+              connection_data.setup_cuid(&buffer_command);
+            } else {
+              buffer_command.setup_cuid(&connection_data);
+            }
+            match parce_done {
+              Ok(full) => full,
+              Err(err) => {
+                warn!(
+                  "Command part error '{}' from client {}",
+                  err.description(), client_socket_label);
+                let code = match err {
+                  PartError::TooLarge(_) => ErrorCodeEnum::TooLarge,
+                  _ => ErrorCodeEnum::InvalidPart,
+                };
+                write_error(
+                  &mut stream,
+                  code,
+                  format!("{} {}", PART_ERROR_TMP, err.description()),
+                  &connection_data,
+                  &framing,
+                  &encoding);
+                buffer_command.clear();
+                close = true;
+                false
+              },
+            }
+          },
+          None => {
+            write_error(
+              &mut stream,
+              ErrorCodeEnum::MalformedFrame,
+              format!("{} {}", FRAME_ERROR_TMP, "Wrong command record."),
+              &connection_data,
+              &framing,
+              &encoding);
+            close = true;
+            false
+          },
+        };
+        if !close {
+          // command ready if full
+          close = !auth && buffer_command.need_auth();
+          if close {
+            warn!(
+              "Authentication failed for command: {} from {}!",
+              buffer_command.description(), client_socket_label);
+            write_error(
+              &mut stream,
+              ErrorCodeEnum::NotAuthorized,
+              NOT_AUTHORIZED_TMP.to_string(),
+              &connection_data,
+              &framing,
+              &encoding);
+          }
+        }
+        hold = done && !close;
+      }
+      if hold {
+        // client without auth has one command in workers
+        let limit = if auth { pipeline_size } else { 1 };
+        if in_flight >= limit || (buffer_command.is_exclusive() && in_flight > 0) {
+          // wait answers
+          break;
+        }
+        // move to buffer
+        buffer_command.set_connection(&connection_data);
+        let buffer_index = send_command_to_workers(
+          command_buffer_size,
+          &buffer_command,
+          &arc_local_command_pool,
+          false,
+          &client_socket_label);
+        if buffer_index >= command_buffer_size {
+          // workers buffer is full, try again later
+          break;
+        }
+        last_cuid = Some(buffer_command.get_cuid());
+        exclusive = buffer_command.is_exclusive();
+        in_flight += 1;
+        hold = false;
+        // clear for next command
+        buffer_command.clear();
+      }
+    }
+    if !close && !connection_data.is_cuid_empty() {
+      let answers = take_answers(
+        &connection_data.get_cuid(),
+        &arc_local_answer_pool,
+        &client_socket_label);
+      for answer in answers {
+        match answer.get_connection() {
+          Some(new_connection_data) => {
+            // answer for command of this connection
+            if in_flight > 0 {
+              in_flight -= 1;
+            }
+            if exclusive {
+              connection_data.copy(&new_connection_data);
+              exclusive = false;
+            }
+          },
+          None => {}
+        }
+        // my be need close connection now
+        close = match <u32 as LookAsTargetAnswerEnum>::as_target_enum(&answer.to_u32()) {
+          AnswerTargetEnum::Quit => {
+            answer.write(&mut stream, &framing, &encoding);
+            true
+          },
+          AnswerTargetEnum::Error => {
+            answer.write(&mut stream, &framing, &encoding);
+            true
+          },
+          AnswerTargetEnum::Skip => {
+            // no write data for client
+            false
+          },
+          AnswerTargetEnum::WhoAreYou => {
+            auth = true;
+            !answer.write(&mut stream, &framing, &encoding)
+          },
+          _ => {
+            !answer.write(&mut stream, &framing, &encoding)
+          },
+        };
+        // codec could be changed by SigIn command
+        encoding = connection_data.get_encoding();
+        if close {
+          break;
+        }
+      }
+    }
+    if !close && !reading {
       thread::sleep_ms(STD_LOOP_DELAY);
-      wait_answer(
-        buffer_index,
-        cuid,
-        &answer_pool,
-        current_connection_data,
-        &connection_data_pool,
-        true,
-        &label)
-    } else {
-      this_answer
     }
-  };
-  result
+  }
+  // end loop
+  info!("Close connection {}", client_socket_label);
+  // set flag of cloce connecion, cuid can be resumed without any command after it
+  let mut closed_cuids: Vec<String> = Vec::new();
+  match last_cuid {
+    Some(last_client_cuid) => closed_cuids.push(last_client_cuid),
+    None => {}
+  }
+  if !connection_data.is_cuid_empty() && !closed_cuids.contains(&connection_data.get_cuid()) {
+    closed_cuids.push(connection_data.get_cuid());
+  }
+  for last_client_cuid in closed_cuids {
+    let end_loop_delay = NOTARGET_DELAY;
+    let iter_limit: u32 = CONNECTION_FINISH_TIMEOUT * 1000 / end_loop_delay;
+    // todo: delte
+    info!("Iter limit {} delay {}", iter_limit, end_loop_delay);
+    let mut iter_index = 0;
+    loop {
+      let mut ok = match arc_local_closed_clients_set.lock() {
+        Ok(mut local_closed_clients_set) => {
+          // set closed connection
+          local_closed_clients_set.insert(last_client_cuid.clone());
+          true
+        },
+        Err(_) => {
+          iter_index += 1;
+          false
+        }
+      };
+      if iter_index >= iter_limit {
+        // fail
+        ok = true;
+        error!("Can't set flag of client {} close connection!", last_client_cuid.clone());
+      }
+      if ok {
+        break;
+      }
+    }
+  }
 }
 
-pub fn init_connection(
+fn listen(
+    listener: TcpListener,
+    acceptor: Option<Arc<SslAcceptor>>,
     options: &ProjectOptions,
+    buffer_size: usize,
     arc_command_pool: Arc<Mutex<Vec<Command>>>,
     arc_answer_pool: Arc<Mutex<Vec<Answer>>>,
-    arc_connection_data_pool: Arc<Mutex<Vec<ClientConnectionData>>>,
     arc_closed_clients_set: Arc<Mutex<HashSet<String>>>) {
 
-  let listener = match TcpListener::bind(options.socket) {
-    Ok(listener) => listener,
-    Err(err) => panic!(format!(
-      "Can't up server on '{:?}' error: {}.", options.socket, err.description())),
-  };
-  let buffer_size = if options.connection_buffer_size < MIN_BUFFER_SIZE {
-    MIN_BUFFER_SIZE
-  } else {
-    options.connection_buffer_size
-  } as usize;
-
   for stream in listener.incoming() {
     let local_options = options.clone();
+    let local_acceptor = acceptor.clone();
     let arc_local_command_pool = arc_command_pool.clone();
     let arc_local_answer_pool = arc_answer_pool.clone();
     let arc_local_closed_clients_set = arc_closed_clients_set.clone();
-    let arc_local_connection_data_pool = arc_connection_data_pool.clone();
     // stream read thread
     match stream {
-      Ok(mut stream) => {
+      Ok(stream) => {
         thread::spawn(move|| {
-            let client_addr = stream.peer_addr().unwrap();
-            let client_socket_label= format!("{}", client_addr);
-            let command_buffer_size = local_options.command_buffer as usize;
-            let mut buffer = vec![0u8; buffer_size];
-            let mut close = false;
-            let mut auth = false;
-            let mut buffer_command = Command::new();
-            let mut last_cuid: Option<String> = None;
-            let mut connection_data: ClientConnectionData = ClientConnectionData::new();
-            while !close {
-              match stream.read(&mut buffer) {
-                Ok(size) => {
-                  if size > 1 {
-                    let new_json_command = get_buffer_command_record(
-                      (0..size).map(|index| buffer[index]).collect(), &client_socket_label);
-
-                    let done = match new_json_command {
-                      Some(json_buffer) => {
-                        let need_cuid = !connection_data.has_cuid();
-                        let parce_done = prepare_command(
-                          &mut buffer_command,
-                          &json_buffer,
-                          &client_addr,
-                          &local_options,
-                          need_cuid);
-                        
-                        if need_cuid {
-                          connection_data.setup_cuid(&buffer_command);
-                        } else {
-                          buffer_command.setup_cuid(&connection_data);
-                        }
-                        parce_done
-                      },
-                      None => false,
-                    };
-                    // command ready if full
-                    close = !auth && buffer_command.need_auth();
-                    if close {
-                      warn!(
-                        "Authentication failed for command: {} from {}!",
-                        buffer_command.description(), client_socket_label);
-                    }
-                    if done && !close {
-                      // move to buffer
-                      let command_cuid = buffer_command.get_cuid();
-                      last_cuid = Some(command_cuid.clone());
-                      let buffer_index = send_command_to_workers(
-                        command_buffer_size,
-                        &buffer_command,
-                        &connection_data,
-                        &arc_local_command_pool,
-                        &arc_local_connection_data_pool,
-                        true,
-                        &client_socket_label);
-
-                      // clear for next command
-                      buffer_command.clear();
-                      // wait answer
-                      let this_answer = wait_answer(
-                        buffer_index,
-                        command_cuid,
-                        &arc_local_answer_pool,
-                        &mut connection_data,
-                        &arc_local_connection_data_pool,
-                        true,
-                        &client_socket_label);
-
-                      match this_answer {
-                        Some(answer) => {
-                          // my be need close connection now
-                          close = match <u32 as LookAsTargetAnswerEnum>::as_target_enum(&answer.to_u32()) {
-                            AnswerTargetEnum::Quit => {
-                              answer.write(&mut stream);
-                              true
-                            },
-                            AnswerTargetEnum::Error => {
-                              answer.write(&mut stream);
-                              true
-                            },
-                            AnswerTargetEnum::Skip => {
-                              // no write data for client
-                              false
-                            },
-                            AnswerTargetEnum::WhoAreYou => {
-                              auth = true;
-                              !answer.write(&mut stream)
-                            },
-                            _ => {
-                              !answer.write(&mut stream)
-                            },
-                          };
-                        }
-                        None => {
-                          let cuid = match last_cuid {
-                            Some(ref cuid_str) => cuid_str.clone(),
-                            None => String::new(),
-                          };
-                          error!(
-                            "No answer for command {} from client {}!",
-                            cuid, client_socket_label);
-                        }
-                      }
-                    }
-                  } else {
-                    close = true;
-                  }
-                },
-                Err(err) => {
-                  close = true;
-                  warn!("connection {} close with error {}", client_socket_label, err);
-                }
-              }
-              if !close {
-                thread::sleep_ms(NOTARGET_DELAY);
+          let client_stream = match local_acceptor {
+            Some(acceptor) => {
+              let timeout = Duration::from_secs(TLS_HANDSHAKE_TIMEOUT as u64);
+              match accept_client(&acceptor, stream, timeout) {
+                Some(client_stream) => client_stream,
+                None => return,
               }
-            }
-            // end loop
-            info!("Close connection {}", client_socket_label);
-            // set flag of cloce connecion
-            match last_cuid {
-              Some(last_client_cuid) => {
-                let end_loop_delay = NOTARGET_DELAY;
-                let iter_limit: u32 = CONNECTION_FINISH_TIMEOUT * 1000 / end_loop_delay;
-                // todo: delte
-                info!("Iter limit {} delay {}", iter_limit, end_loop_delay);
-                let mut iter_index = 0;
-                loop {
-                  let mut ok = match arc_local_closed_clients_set.lock() {
-                    Ok(mut local_closed_clients_set) => {
-                      // set closed connection
-                      local_closed_clients_set.insert(last_client_cuid.clone());
-                      true
-                    },
-                    Err(_) => {
-                      iter_index += 1;
-                      false
-                    }
-                  };
-                  if iter_index >= iter_limit {
-                    // fail
-                    ok = true;
-                    error!("Can't set flag of client {} close connection!", last_client_cuid.clone());
-                  }
-                  if ok {
-                    break;
-                  }
-                }
-              },
-              None => {}
-            }
+            },
+            None => ClientStream::Plain(stream),
+          };
+          client_loop(
+            client_stream,
+            local_options,
+            buffer_size,
+            arc_local_command_pool,
+            arc_local_answer_pool,
+            arc_local_closed_clients_set);
         });
       },
       Err(err) => {
         panic!(format!(
-          "Can't up server on '{:?}' error: {}.", options.socket, err.description()));
+          "Can't up server on '{:?}' error: {}.", listener.local_addr(), err.description()));
       }
     }
   }
 }
 
-// -- tests --
+pub fn init_connection(
+    options: &ProjectOptions,
+    arc_command_pool: Arc<Mutex<Vec<Command>>>,
+    arc_answer_pool: Arc<Mutex<Vec<Answer>>>,
+    arc_closed_clients_set: Arc<Mutex<HashSet<String>>>) {
+
+  let buffer_size = if options.connection_buffer_size < MIN_BUFFER_SIZE {
+    MIN_BUFFER_SIZE
+  } else {
+    options.connection_buffer_size
+  } as usize;
+
+  match options.tls_socket {
+    Some(tls_socket) => {
+      let listener = match TcpListener::bind(tls_socket) {
+        Ok(listener) => listener,
+        Err(err) => panic!(format!(
+          "Can't up tls server on '{:?}' error: {}.", tls_socket, err.description())),
+      };
+      let acceptor = match create_acceptor(&options) {
+        Ok(acceptor) => Arc::new(acceptor),
+        Err(err) => panic!(format!(
+          "Can't use certificate '{}' and key '{}' error: {}.",
+          options.tls_cert, options.tls_key, err)),
+      };
+      if options.tls_only {
+        listen(
+          listener, Some(acceptor), &options, buffer_size,
+          arc_command_pool, arc_answer_pool, arc_closed_clients_set);
+        return;
+      }
+      let local_options = options.clone();
+      let arc_local_command_pool = arc_command_pool.clone();
+      let arc_local_answer_pool = arc_answer_pool.clone();
+      let arc_local_closed_clients_set = arc_closed_clients_set.clone();
+      thread::spawn(move|| {
+        listen(
+          listener, Some(acceptor), &local_options, buffer_size,
+          arc_local_command_pool, arc_local_answer_pool, arc_local_closed_clients_set);
+      });
+    },
+    None => {}
+  }
+
+  let listener = match TcpListener::bind(options.socket) {
+    Ok(listener) => listener,
+    Err(err) => panic!(format!(
+      "Can't up server on '{:?}' error: {}.", options.socket, err.description())),
+  };
+  listen(
+    listener, None, &options, buffer_size,
+    arc_command_pool, arc_answer_pool, arc_closed_clients_set);
+}
+
 #[cfg(test)]
 mod tests {
   extern crate rand;
+  use codec::{
+    EncodingEnum, FramingEnum, MsgPackValue, encode_msgpack_record, decode_msgpack_record};
   use connection::{
-    get_buffer_command_record, prepare_command};
+    certificate_login, get_buffer_command_record, prepare_command, send_command_to_workers,
+    take_answers};
+  use crypto::digest::Digest;
+  use crypto::sha2::Sha256;
+  use std::sync::Mutex;
   use rand::Rng;
   use std::env;
   use std::fs::File;
@@ -408,8 +591,12 @@ mod tests {
   use std::error::Error;
   use std::net::{SocketAddr, Ipv4Addr, SocketAddrV4};
   use transport::{
-    Command, JsonBufferCommand, CreateTestRecord, TransportConstructor};
-  use options::configuration::{JsonReader, ProjectOptions};
+    Answer, Command, JsonBufferCommand, CreateTestRecord, TransportConstructor,
+    AnswerWriter, CommandCreationAnswer, ClientConnectionData};
+  use handler::exec::get_answer_method;
+  use options::configuration::{JsonReader, ProjectOptions, QueueOptions, ClientCredentials};
+  use protocol::{AnswerTargetEnum, ClientGroupEnum, CommandTargetEnum, TargetAsDigit};
+  use state::BrokerState;
 
   fn create_options() -> ProjectOptions {
     let mut tmp_path = env::temp_dir();
@@ -442,7 +629,7 @@ mod tests {
   fn test_buffer_command_record() {
     let label = "test".to_string();
     let json_text1 = "{\"target\": 2, \"part\": false, \"data\": \"hello\", \"cid\": \"\"}".to_string();
-    match get_buffer_command_record(json_text1.into_bytes(), &label) {
+    match get_buffer_command_record(json_text1.into_bytes(), &EncodingEnum::Json, &label) {
       Some(record) => {
         assert!(true);
       },
@@ -451,7 +638,7 @@ mod tests {
       }
     }
     let json_text2 = "{\"target\": 2, \"part\": false, \"data1\": \"hello\", \"cid\": \"\"}".to_string();
-    match get_buffer_command_record(json_text2.into_bytes(), &label) {
+    match get_buffer_command_record(json_text2.into_bytes(), &EncodingEnum::Json, &label) {
       Some(record) => {
         assert!(false);
       },
@@ -461,6 +648,58 @@ mod tests {
     }
   }
 
+  #[test]
+  fn test_buffer_command_record_msgpack() {
+    let label = "test".to_string();
+    let body = encode_msgpack_record(&vec![
+      ("target", MsgPackValue::Uint(2)),
+      ("part", MsgPackValue::Bool(false)),
+      ("data", MsgPackValue::Str("hello".to_string())),
+      ("cid", MsgPackValue::Str(String::new()))]);
+    assert!(get_buffer_command_record(body, &EncodingEnum::MsgPack, &label).is_some());
+    let body_without_data = encode_msgpack_record(&vec![
+      ("target", MsgPackValue::Uint(2)),
+      ("part", MsgPackValue::Bool(false)),
+      ("cid", MsgPackValue::Str(String::new()))]);
+    assert!(get_buffer_command_record(body_without_data, &EncodingEnum::MsgPack, &label).is_none());
+  }
+
+  #[test]
+  fn test_msgpack_blob_task() {
+    // binary payload of publish goes to server in Task answer as is
+    let label = "test".to_string();
+    let blob = vec![0u8, 159, 146, 150, 255];
+    let body = encode_msgpack_record(&vec![
+      ("target", MsgPackValue::Uint(8)),
+      ("part", MsgPackValue::Bool(false)),
+      ("data", MsgPackValue::Str("{\"queue\": \"mail\", \"data\": \"\"}".to_string())),
+      ("cid", MsgPackValue::Str(String::new())),
+      ("blob", MsgPackValue::Bin(blob.clone()))]);
+    let record = get_buffer_command_record(body, &EncodingEnum::MsgPack, &label).unwrap();
+    let mut options = create_options();
+    options.queues.push(QueueOptions::new("mail"));
+    let addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(100, 100, 100, 1), 1000));
+    let mut command = Command::new();
+    assert!(prepare_command(&mut command, &record, &addr, &options, true).unwrap());
+    // worker takes copy of command from pool
+    let pool = Mutex::new(vec![Command::new()]);
+    assert_eq!(send_command_to_workers(1, &command, &pool, false, &label), 0);
+    let mut work_command = pool.lock().unwrap()[0].clone();
+    let mut connection_data = ClientConnectionData::new();
+    connection_data.set_group(ClientGroupEnum::Service.to_u32());
+    connection_data.set_version(2);
+    let state = BrokerState::new();
+    work_command.get_answer(&options, &mut connection_data, &state);
+    assert!(connection_data.get_blob().is_empty());
+
+    state.consume(options.get_queue("mail").unwrap(), "server".to_string());
+    let routed = state.take_routed();
+    let mut frame: Vec<u8> = Vec::new();
+    assert!(routed[0].write(&mut frame, &FramingEnum::Length, &EncodingEnum::MsgPack));
+    let fields = decode_msgpack_record(&frame[4..]).unwrap();
+    assert!(fields.contains(&("blob".to_string(), MsgPackValue::Bin(blob))));
+  }
+
   #[test]
   fn test_prepare_command_full() {
     let case = 1;
@@ -468,7 +707,7 @@ mod tests {
     let mut command = Command::new();
     let addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(100, 100, 100, 1), 1000));
     let options = create_options();
-    prepare_command(&mut command, &json_data, &addr, &options, true);
+    assert!(prepare_command(&mut command, &json_data, &addr, &options, true).unwrap());
     assert!(command.check(case));
   }
 
@@ -479,7 +718,133 @@ mod tests {
     let mut command = Command::new();
     let addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(100, 100, 100, 1), 1000));
     let options = create_options();
-    prepare_command(&mut command, &json_data, &addr, &options, true);
+    assert!(!prepare_command(&mut command, &json_data, &addr, &options, true).unwrap());
     assert!(command.check(case));
   }
+
+  #[test]
+  fn test_prepare_command_sequence() {
+    let label = "test".to_string();
+    let addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(100, 100, 100, 1), 1000));
+    let mut options = create_options();
+    // sha256 of "abcdef"
+    let checksum = "bef57ec7f53a6d40beb640a780a639c83bc29ac8a9816f1fc6c5c6dcd93c4721";
+    let chunks = vec![
+      format!(
+        "{{\"target\": 5, \"part\": true, \"cid\": \"\", \"data\": \"abc\", \"seq\": 0, \"total\": 6, \"checksum\": \"{}\"}}",
+        checksum),
+      "{\"target\": 5, \"part\": false, \"cid\": \"\", \"data\": \"def\", \"seq\": 1}".to_string(),
+      "{\"target\": 5, \"part\": false, \"cid\": \"\", \"data\": \"xyz\", \"seq\": 1}".to_string(),
+      "{\"target\": 5, \"part\": false, \"cid\": \"\", \"data\": \"def\", \"seq\": 2}".to_string()];
+    let records: Vec<JsonBufferCommand> = chunks.into_iter().map(
+      |chunk| get_buffer_command_record(chunk.into_bytes(), &EncodingEnum::Json, &label).unwrap()
+    ).collect();
+
+    let mut command = Command::new();
+    assert!(!prepare_command(&mut command, &records[0], &addr, &options, true).unwrap());
+    assert!(prepare_command(&mut command, &records[1], &addr, &options, false).unwrap());
+
+    let mut corrupt_command = Command::new();
+    prepare_command(&mut corrupt_command, &records[0], &addr, &options, true).unwrap();
+    assert!(prepare_command(&mut corrupt_command, &records[2], &addr, &options, false).is_err());
+
+    let mut lost_command = Command::new();
+    prepare_command(&mut lost_command, &records[0], &addr, &options, true).unwrap();
+    assert!(prepare_command(&mut lost_command, &records[3], &addr, &options, false).is_err());
+
+    options.max_command_size = 4;
+    let mut large_command = Command::new();
+    assert!(prepare_command(&mut large_command, &records[0], &addr, &options, true).is_err());
+  }
+
+  #[test]
+  fn test_prepare_command_blob_parts() {
+    let label = "test".to_string();
+    let addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(100, 100, 100, 1), 1000));
+    let mut options = create_options();
+    let record = |part: bool, blob: Vec<u8>, checksum: Option<String>| {
+      let mut fields = vec![
+        ("target", MsgPackValue::Uint(5)),
+        ("part", MsgPackValue::Bool(part)),
+        ("data", MsgPackValue::Str("abc".to_string())),
+        ("cid", MsgPackValue::Str(String::new())),
+        ("blob", MsgPackValue::Bin(blob))];
+      match checksum {
+        Some(checksum) => {
+          fields.push(("total", MsgPackValue::Uint(9)));
+          fields.push(("checksum", MsgPackValue::Str(checksum)));
+        },
+        None => {}
+      }
+      let body = encode_msgpack_record(&fields);
+      get_buffer_command_record(body, &EncodingEnum::MsgPack, &label).unwrap()
+    };
+    // checksum of data and blob of all parts
+    let mut hasher = Sha256::new();
+    hasher.input_str("abcabc");
+    hasher.input(&[0, 1, 255]);
+    let checksum = hasher.result_str();
+    let mut command = Command::new();
+    let first = record(true, vec![0], Some(checksum.clone()));
+    assert!(!prepare_command(&mut command, &first, &addr, &options, true).unwrap());
+    let last = record(false, vec![1, 255], None);
+    assert!(prepare_command(&mut command, &last, &addr, &options, false).unwrap());
+
+    let mut corrupt_command = Command::new();
+    prepare_command(&mut corrupt_command, &first, &addr, &options, true).unwrap();
+    let corrupt = record(false, vec![1, 254], None);
+    assert!(prepare_command(&mut corrupt_command, &corrupt, &addr, &options, false).is_err());
+
+    // blob parts are limited by size of command
+    options.max_command_size = 32;
+    let mut large_command = Command::new();
+    let part = record(true, vec![7; 10], None);
+    assert!(!prepare_command(&mut large_command, &part, &addr, &options, true).unwrap());
+    assert!(!prepare_command(&mut large_command, &part, &addr, &options, false).unwrap());
+    assert!(prepare_command(&mut large_command, &part, &addr, &options, false).is_err());
+  }
+
+  #[test]
+  fn test_certificate_login() {
+    let label = "test".to_string();
+    let mut options = ProjectOptions::new();
+    options.credentials.push(ClientCredentials {
+      identity: "service1".to_string(),
+      secret: String::new(),
+      group: ClientGroupEnum::Server.to_u32(),
+    });
+    let state = BrokerState::new();
+    let mut connection_data = ClientConnectionData::new();
+    assert!(!certificate_login(
+      &mut connection_data, Some("service2".to_string()), &options, &label));
+    assert!(!connection_data.is_auth());
+    assert!(certificate_login(
+      &mut connection_data, Some("service1".to_string()), &options, &label));
+    assert!(connection_data.is_auth());
+    assert_eq!(connection_data.get_group(), ClientGroupEnum::Server.to_u32());
+    // identity of certificate can't be changed by SigIn
+    let sigin = get_answer_method(CommandTargetEnum::SigIn, 1);
+    let request = "{\"versions\": [2], \"identity\": \"manager1\"}".to_string();
+    let (target, _) = sigin(&request, &mut connection_data, &options, &state);
+    assert_eq!(target, AnswerTargetEnum::Error.to_u32());
+    assert_eq!(connection_data.get_identity(), "service1");
+  }
+
+  #[test]
+  fn test_take_answers() {
+    let label = "test".to_string();
+    let mut answer_pool: Vec<Answer> = Vec::new();
+    for index in 0..4 {
+      let mut answer = Answer::new();
+      if index != 2 {
+        answer.complete(if index % 2 == 0 { "client_1" } else { "client_2" }.to_string());
+      }
+      answer_pool.push(answer);
+    }
+    let pool = Mutex::new(answer_pool);
+    assert_eq!(take_answers(&"client_2".to_string(), &pool, &label).len(), 2);
+    assert_eq!(take_answers(&"client_2".to_string(), &pool, &label).len(), 0);
+    assert_eq!(take_answers(&"client_1".to_string(), &pool, &label).len(), 1);
+    assert!(pool.lock().unwrap().iter().all(|answer| answer.is_empty()));
+  }
 }
@@ -3,11 +3,54 @@ pub mod common {
   pub static STD_LOOP_DELAY: u32 = 10;
   pub static NOTARGET_DELAY: u32 = 100;
   pub static MIN_COMMAND_POOL_SIZE: usize = 8;
+  pub static PIPELINE_SIZE: u32 = 32;
   pub static MIN_BUFFER_SIZE: u32 = 2048;
+  pub static MAX_FRAME_SIZE: u32 = 1048576;
+  pub const FRAME_HEADER_SIZE: usize = 4;
+  pub static MAX_COMMAND_SIZE: u32 = 16777216; // data of multi-part command
   pub static CONNECTION_FINISH_TIMEOUT: u32 = 60; // sec
+  pub static ANSWER_SHIP_TIMEOUT: u32 = 5; // sec, answer is dropped without free cell in pool
+  pub static TLS_HANDSHAKE_TIMEOUT: u32 = 10; // sec
+  pub static HEARTBEAT_INTERVAL: u32 = 30; // sec
+  pub static MIN_HEARTBEAT_INTERVAL: u32 = 1; // sec
+  pub static HEARTBEAT_MISSES: u32 = 3;
   pub static VERIFICATION_LINE_SIZE: usize = 128;
+  pub static MIN_CLIENT_NONCE_SIZE: usize = 16;
+  pub static AUTH_MAX_FAILURES: u32 = 5;
+  pub static AUTH_BAN_TIME: u32 = 60; // sec, doubled by each next failure
+  pub static AUTH_MAX_BAN_TIME: u32 = 3600; // sec
+  pub static AUTH_FAILURE_WINDOW: u32 = 600; // sec, failures are counted from first one in it
+  pub static RESUME_TOKEN_TTL: u32 = 3600; // sec
+  pub static RESUME_KEY_SIZE: usize = 64;
+  pub static QUEUE_MAX_SIZE: u32 = 100000; // tasks
+  pub static QUEUE_MAX_PRIORITIES: u32 = 16; // levels
+  pub static TASK_VISIBILITY_TIMEOUT: u32 = 60; // sec, task without Ack returns to queue
+  // protocol before versions negotiation
+  pub const LEGACY_PROTOCOL_VERSION: u32 = 1;
+  pub const PROTOCOL_VERSION: u32 = 2;
 }
 
 pub mod messages {
   pub static AUTH_FAILED_TMP: &'static str = "Auth filed!";
+  pub static FRAME_ERROR_TMP: &'static str = "Frame error!";
+  pub static CODEC_ERROR_TMP: &'static str = "Codec not supported!";
+  pub static VERSION_ERROR_TMP: &'static str = "No supported protocol version!";
+  pub static UNKNOWN_TARGET_TMP: &'static str = "Unknown command target!";
+  pub static NOT_AUTHORIZED_TMP: &'static str = "Authentication required!";
+  pub static AUTH_DONE_TMP: &'static str = "Client is authenticated already!";
+  pub static HEARTBEAT_TIMEOUT_TMP: &'static str = "Heartbeat timeout!";
+  pub static CLIENT_DATA_ERROR_TMP: &'static str = "Client data error!";
+  pub static CLIENT_GROUP_ERROR_TMP: &'static str = "Client group is not allowed!";
+  pub static PART_ERROR_TMP: &'static str = "Command part error!";
+  pub static FORBIDDEN_TMP: &'static str = "Command is forbidden!";
+  pub static RESUME_TOKEN_ERROR_TMP: &'static str = "Resume token is wrong or expired!";
+  pub static BANNED_TMP: &'static str = "Too many auth failures, try again after (sec):";
+  pub static QUEUE_NOT_FOUND_TMP: &'static str = "Queue not found:";
+  pub static QUEUE_FULL_TMP: &'static str = "Queue is full:";
+  pub static TASK_REQUEST_ERROR_TMP: &'static str = "Task request error!";
+  pub static TASK_NOT_FOUND_TMP: &'static str = "Task is not in flight for this client:";
+  pub static SCHEDULED_NOT_FOUND_TMP: &'static str = "Scheduled task not found:";
+  pub static TOPIC_REQUEST_ERROR_TMP: &'static str = "Topic request error!";
+  pub static NOT_SUBSCRIBED_TMP: &'static str = "Client is not subscribed to topic:";
+  pub static NO_CAPABLE_SERVER_TMP: &'static str = "No connected server can handle method:";
 }
@@ -1,89 +1,725 @@
 pub mod exec {
+  extern crate time;
+
+  use codec::{EncodingEnum, FramingEnum};
   use common::helpers::get_random_string;
-  use consts::common::VERIFICATION_LINE_SIZE;
-  use consts::messages::AUTH_FAILED_TMP;
+  use consts::common::{
+    VERIFICATION_LINE_SIZE, LEGACY_PROTOCOL_VERSION, PROTOCOL_VERSION,
+    MIN_HEARTBEAT_INTERVAL, MIN_CLIENT_NONCE_SIZE};
+  use consts::messages::{
+    AUTH_FAILED_TMP, AUTH_DONE_TMP, CODEC_ERROR_TMP, VERSION_ERROR_TMP,
+    UNKNOWN_TARGET_TMP, CLIENT_DATA_ERROR_TMP, CLIENT_GROUP_ERROR_TMP, FORBIDDEN_TMP, BANNED_TMP,
+    RESUME_TOKEN_ERROR_TMP, QUEUE_NOT_FOUND_TMP, QUEUE_FULL_TMP, TASK_REQUEST_ERROR_TMP,
+    TASK_NOT_FOUND_TMP, SCHEDULED_NOT_FOUND_TMP, TOPIC_REQUEST_ERROR_TMP, NOT_SUBSCRIBED_TMP,
+    NO_CAPABLE_SERVER_TMP};
   use crypto::sha1::Sha1;
+  use crypto::sha2::Sha256;
+  use crypto::hmac::Hmac;
+  use crypto::mac::{Mac, MacResult};
   use crypto::digest::Digest;
+  use crypto::util::fixed_time_eq;
   use protocol::{
-    CommandTargetEnum, AnswerTargetEnum, TargetAsDigit, ClientDescription};
+    CommandTargetEnum, AnswerTargetEnum, TargetAsDigit, ClientDescription,
+    SigInRequest, SigInAnswer, ErrorCodeEnum, AuthRequest, LookAsTargetCommandEnum,
+    ClientGroupEnum, PublishRequest, ConsumeRequest, TaskRequest, DeadRequest, ResultRequest,
+    TopicRequest};
+  use rustc_serialize::hex::{FromHex, ToHex};
   use std::clone::Clone;
   use transport::{
     Answer, Command, CommandCreationAnswer, ClientConnectionData, CuidSource};
-  use options::configuration::ProjectOptions;
+  use options::configuration::{ProjectOptions, QueueOptions};
+  use queue::NewTask;
   use rustc_serialize::json;
+  use state::BrokerState;
 
   // -- public traits --
   pub trait CommandHandle {
-    fn execute(&mut self, options: &ProjectOptions, connection_data: &mut ClientConnectionData) -> Answer;
+    fn execute(
+      &mut self,
+      options: &ProjectOptions,
+      connection_data: &mut ClientConnectionData,
+      state: &BrokerState) -> Answer;
+  }
+  // === helpers ===
+  fn error_answer(
+      code: ErrorCodeEnum,
+      message: String,
+      connection_data: &ClientConnectionData) -> (u32, String) {
+    (AnswerTargetEnum::Error.to_u32(), code.answer_data(&message, connection_data.get_version()))
+  }
+  // error of request, connection is not closed
+  fn rejected_answer(
+      code: ErrorCodeEnum,
+      message: String,
+      connection_data: &ClientConnectionData) -> (u32, String) {
+    (AnswerTargetEnum::Rejected.to_u32(), code.answer_data(&message, connection_data.get_version()))
+  }
+
+  // options of queue which client can use
+  fn allowed_queue<'a>(
+      name: &str,
+      connection_data: &ClientConnectionData,
+      options: &'a ProjectOptions) -> Result<&'a QueueOptions, (u32, String)> {
+    match options.get_queue(name) {
+      Some(queue) => {
+        if options.allows_queue(&connection_data.get_identity(), connection_data.get_group(), name) {
+          Ok(queue)
+        } else {
+          warn!("Queue '{}' is forbidden for client {}", name, connection_data.get_cuid());
+          let data = ErrorCodeEnum::Forbidden.answer_data(FORBIDDEN_TMP, connection_data.get_version());
+          Err((AnswerTargetEnum::Forbidden.to_u32(), data))
+        }
+      },
+      None => {
+        let msg = format!("{} {}", QUEUE_NOT_FOUND_TMP, name);
+        Err(rejected_answer(ErrorCodeEnum::QueueNotFound, msg, connection_data))
+      }
+    }
+  }
+  // sha1 of client key, server key and secret, old clients only
+  fn check_legacy_auth(client_data: &String, server_key: &[u8], secret: &String) -> bool {
+    let data = client_data.as_bytes();
+    if data.len() <= VERIFICATION_LINE_SIZE {
+      return false;
+    }
+    let (client_key, client_hex) = data.split_at(VERIFICATION_LINE_SIZE);
+    let mut hasher = Sha1::new();
+    hasher.input(client_key);
+    hasher.input(server_key);
+    hasher.input(secret.as_bytes());
+    let hex = hasher.result_str();
+    fixed_time_eq(hex.as_bytes(), &client_hex.to_ascii_lowercase())
+  }
+
+  fn check_hmac_auth(record: &AuthRequest, server_key: &[u8], secret: &String) -> bool {
+    let nonce = record.get_nonce();
+    // client nonce protects client from a chosen server key
+    if nonce.len() < MIN_CLIENT_NONCE_SIZE || nonce.as_bytes() == server_key {
+      return false;
+    }
+    let client_code = match record.get_hmac().from_hex() {
+      Ok(code) => code,
+      Err(_) => return false,
+    };
+    let mut hmac = Hmac::new(Sha256::new(), secret.as_bytes());
+    hmac.input(server_key);
+    hmac.input(nonce.as_bytes());
+    // compare of MacResult takes fixed time
+    hmac.result() == MacResult::new_from_owned(client_code)
+  }
+  // resume token: cuid, expiry time and hmac-sha256 of it with group and identity
+  fn resume_token_code(
+      cuid: &str, expires: i64, connection_data: &ClientConnectionData, key: &str) -> MacResult {
+    let mut hmac = Hmac::new(Sha256::new(), key.as_bytes());
+    hmac.input(format!(
+      "{}:{}:{}:{}",
+      cuid, expires, connection_data.get_group(), connection_data.get_identity()).as_bytes());
+    hmac.result()
+  }
+
+  fn get_resume_key<'a>(options: &'a ProjectOptions, state: &'a BrokerState) -> &'a str {
+    if options.resume_secret.is_empty() {
+      state.get_resume_key()
+    } else {
+      &options.resume_secret
+    }
+  }
+
+  fn create_resume_token(
+      connection_data: &ClientConnectionData, options: &ProjectOptions, state: &BrokerState) -> String {
+    let cuid = connection_data.get_cuid();
+    let expires = time::get_time().sec + options.resume_ttl as i64;
+    let code = resume_token_code(&cuid, expires, connection_data, get_resume_key(options, state));
+    format!("{}:{}:{}", cuid, expires, code.code().to_hex())
+  }
+
+  fn check_resume_token(
+      token: &str,
+      cid: &str,
+      connection_data: &ClientConnectionData,
+      options: &ProjectOptions,
+      state: &BrokerState) -> bool {
+    // cuid has ':' of address
+    let parts: Vec<&str> = token.rsplitn(3, ':').collect();
+    if parts.len() != 3 || parts[2] != cid {
+      return false;
+    }
+    let expires = match parts[1].parse::<i64>() {
+      Ok(expires) if expires > time::get_time().sec => expires,
+      _ => return false,
+    };
+    let key = get_resume_key(options, state);
+    match parts[0].from_hex() {
+      Ok(code) => resume_token_code(cid, expires, connection_data, key) == MacResult::new_from_owned(code),
+      Err(_) => false,
+    }
   }
   // === handlers ===
   fn answer_empty(
       client_data: &String,
       connection_data: &mut ClientConnectionData,
-      options: &ProjectOptions) -> (u32, String) {
+      options: &ProjectOptions,
+      state: &BrokerState) -> (u32, String) {
     (AnswerTargetEnum::Unknown.to_u32(), String::new())
   }
 
+  fn answer_unknown_target(
+      client_data: &String,
+      connection_data: &mut ClientConnectionData,
+      options: &ProjectOptions,
+      state: &BrokerState) -> (u32, String) {
+    error_answer(ErrorCodeEnum::UnknownTarget, UNKNOWN_TARGET_TMP.to_string(), connection_data)
+  }
+
+  fn answer_forbidden(
+      client_data: &String,
+      connection_data: &mut ClientConnectionData,
+      options: &ProjectOptions,
+      state: &BrokerState) -> (u32, String) {
+    // connection is not closed
+    let data = ErrorCodeEnum::Forbidden.answer_data(FORBIDDEN_TMP, connection_data.get_version());
+    (AnswerTargetEnum::Forbidden.to_u32(), data)
+  }
+
   fn client_fast_quit_rquest(
       client_data: &String,
       connection_data: &mut ClientConnectionData,
-      options: &ProjectOptions) -> (u32, String) {
+      options: &ProjectOptions,
+      state: &BrokerState) -> (u32, String) {
     if !client_data.is_empty() {
       warn!("Client {} requrst a quit with message: {}", connection_data.get_cuid(), client_data); 
     }  
     (AnswerTargetEnum::Quit.to_u32(), "buy".to_string())
   }
       
+  fn answer_pong(
+      client_data: &String,
+      connection_data: &mut ClientConnectionData,
+      options: &ProjectOptions,
+      state: &BrokerState) -> (u32, String) {
+    (AnswerTargetEnum::Pong.to_u32(), client_data.clone())
+  }
+
   fn answer_verification_request(
       client_data: &String,
       connection_data: &mut ClientConnectionData,
-      options: &ProjectOptions) -> (u32, String) {
+      options: &ProjectOptions,
+      state: &BrokerState) -> (u32, String) {
+    // identity, acl and resume tokens of authenticated client are fixed
+    if connection_data.is_auth() {
+      return error_answer(ErrorCodeEnum::BadRequest, AUTH_DONE_TMP.to_string(), connection_data);
+    }
+    // empty data or not json from old clients
+    let json_record: Option<SigInRequest> = if client_data.is_empty() {
+      None
+    } else {
+      match json::decode(client_data) {
+        Ok(record) => Some(record),
+        Err(err) => {
+          warn!("SigIn data '{}' is not options of connection: {}", client_data, err);
+          None
+        },
+      }
+    };
+    let (codec, versions, heartbeat, identity) = match json_record {
+      Some(record) => (
+        record.get_codec(), record.get_versions(), record.get_heartbeat(), record.get_identity()),
+      None => (None, None, None, None),
+    };
+    match identity {
+      Some(identity) => connection_data.set_identity(identity),
+      None => {}
+    }
+    // client can ask more frequent heartbeats only
+    let heartbeat = match heartbeat {
+      Some(interval) if interval < MIN_HEARTBEAT_INTERVAL => MIN_HEARTBEAT_INTERVAL,
+      Some(interval) if interval < options.heartbeat_interval => interval,
+      _ => options.heartbeat_interval,
+    };
+    connection_data.set_heartbeat(heartbeat);
+    match codec {
+      Some(codec) => {
+        match EncodingEnum::from_name(&codec) {
+          // binary body can contain line separator
+          Some(EncodingEnum::MsgPack) if options.framing == FramingEnum::Lines => {
+            let msg = format!("{} '{}' with lines framing", CODEC_ERROR_TMP, codec);
+            return error_answer(ErrorCodeEnum::UnsupportedCodec, msg, connection_data);
+          },
+          Some(encoding) => {
+            connection_data.set_encoding(encoding);
+          },
+          None => {
+            let msg = format!("{} '{}'", CODEC_ERROR_TMP, codec);
+            return error_answer(ErrorCodeEnum::UnsupportedCodec, msg, connection_data);
+          }
+        }
+      },
+      None => {}
+    }
 
     let key = get_random_string(VERIFICATION_LINE_SIZE);
     connection_data.set_temp_data(key.clone().into_bytes());
-    (AnswerTargetEnum::VerificationRequest.to_u32(), key)
+    match versions {
+      Some(versions) => {
+        // newest version which both sides know
+        let version = versions.iter().filter(
+          |version| **version >= LEGACY_PROTOCOL_VERSION && **version <= PROTOCOL_VERSION).max();
+        match version {
+          Some(version) => {
+            connection_data.set_version(*version);
+            let answer = SigInAnswer::new(
+              key, *version, connection_data.get_encoding().name(), heartbeat);
+            (AnswerTargetEnum::VerificationRequest.to_u32(), json::encode(&answer).unwrap())
+          },
+          None => {
+            let msg = format!("{} {:?}", VERSION_ERROR_TMP, versions);
+            error_answer(ErrorCodeEnum::UnsupportedVersion, msg, connection_data)
+          }
+        }
+      },
+      // old client, key only
+      None => (AnswerTargetEnum::VerificationRequest.to_u32(), key),
+    }
   }
 
   fn answer_check_auth(
       client_data: &String,
       connection_data: &mut ClientConnectionData,
-      options: &ProjectOptions) -> (u32, String) {
-    let mut hasher = Sha1::new();
-    let mut key_line = String::new();
-    let mut client_hex = String::new();
-    let mut index = 0;
-    let data: Vec<char> = client_data.chars().collect();
-    let secret: Vec<char> = options.secret.chars().collect();
-    for ch in data {
-      if index < VERIFICATION_LINE_SIZE {
-        key_line.push(ch);
-      } else {
-        client_hex.push(ch);
+      options: &ProjectOptions,
+      state: &BrokerState) -> (u32, String) {
+    // server key from SigIn is single-use
+    let server_key = connection_data.get_temp_data();
+    connection_data.set_temp_data(Vec::new());
+    // client with identity has own secret and group
+    let identity = connection_data.get_identity();
+    let address = connection_data.get_address();
+    let ban = state.auth_ban(&address, &identity);
+    if ban > 0 {
+      warn!("Auth of banned client {} '{}'", address, identity);
+      let msg = format!("{} {}", BANNED_TMP, ban);
+      return error_answer(ErrorCodeEnum::Banned, msg, connection_data);
+    }
+    let (secrets, group) = if identity.is_empty() {
+      // shared secrets in rotation
+      (options.active_secrets(time::get_time().sec), None)
+    } else {
+      match options.get_credentials(&identity) {
+        Some(credentials) if !credentials.secret.is_empty() => (
+          vec![(identity.clone(), credentials.secret.clone())], Some(credentials.group)),
+        Some(_) => (Vec::new(), None),
+        None => {
+          warn!("Unknown client identity '{}'", identity);
+          (Vec::new(), None)
+        }
+      }
+    };
+    let record = json::decode::<AuthRequest>(client_data);
+    let legacy = record.is_err();
+    if legacy && options.legacy_auth {
+      warn!("Client {} uses legacy auth", address);
+    }
+    let used_secret = if server_key.is_empty() {
+      None
+    } else {
+      secrets.iter().find(|&&(_, ref secret)| match record {
+        Ok(ref record) => check_hmac_auth(record, &server_key, secret),
+        Err(_) if options.legacy_auth => check_legacy_auth(client_data, &server_key, secret),
+        Err(_) => false,
+      }).map(|&(ref name, _)| name.clone())
+    };
+
+    match used_secret {
+      Some(name) => {
+        info!("Client {} '{}' auth with secret '{}'", address, identity, name);
+        match group {
+          Some(group) => connection_data.set_group(group),
+          None => {}
+        }
+        state.auth_passed(&address, &identity);
+        connection_data.set_auth(true);
+        (AnswerTargetEnum::WhoAreYou.to_u32(), "OK".to_string())
+      },
+      None => {
+        state.auth_failed(&address, &identity, options, time::get_time().sec);
+        let msg = format!("{} {}", AUTH_FAILED_TMP, "Check secret key?").to_string();
+        error_answer(ErrorCodeEnum::AuthFailed, msg, connection_data)
+      }
+    }
+  }
+
+  // bans are managed by manager with own credentials only
+  fn is_credentialed_manager(
+      connection_data: &ClientConnectionData,
+      options: &ProjectOptions) -> bool {
+    let manager = ClientGroupEnum::Manager.to_u32();
+    connection_data.get_group() == manager &&
+      match options.get_credentials(&connection_data.get_identity()) {
+        Some(credentials) => credentials.group == manager,
+        None => false,
+      }
+  }
+
+  fn answer_ban_list(
+      client_data: &String,
+      connection_data: &mut ClientConnectionData,
+      options: &ProjectOptions,
+      state: &BrokerState) -> (u32, String) {
+    if !is_credentialed_manager(connection_data, options) {
+      return answer_forbidden(client_data, connection_data, options, state);
+    }
+    (AnswerTargetEnum::BanList.to_u32(), json::encode(&state.get_bans()).unwrap())
+  }
+
+  fn answer_clear_bans(
+      client_data: &String,
+      connection_data: &mut ClientConnectionData,
+      options: &ProjectOptions,
+      state: &BrokerState) -> (u32, String) {
+    // data is address or identity, empty data - all
+    if !is_credentialed_manager(connection_data, options) {
+      return answer_forbidden(client_data, connection_data, options, state);
+    }
+    let count = state.clear_bans(client_data.trim());
+    info!("Manager {} cleared {} bans", connection_data.get_cuid(), count);
+    (AnswerTargetEnum::Done.to_u32(), count.to_string())
+  }
+
+  fn answer_publish(
+      client_data: &String,
+      connection_data: &mut ClientConnectionData,
+      options: &ProjectOptions,
+      state: &BrokerState) -> (u32, String) {
+    // services publish tasks
+    if connection_data.get_group() != ClientGroupEnum::Service.to_u32() {
+      return answer_forbidden(client_data, connection_data, options, state);
+    }
+    let record: PublishRequest = match json::decode(client_data) {
+      Ok(record) => record,
+      Err(err) => {
+        warn!("Publish data of client {} error: {}", connection_data.get_cuid(), err);
+        return rejected_answer(
+          ErrorCodeEnum::BadRequest, TASK_REQUEST_ERROR_TMP.to_string(), connection_data);
+      }
+    };
+    let queue = match allowed_queue(&record.get_queue(), connection_data, options) {
+      Ok(queue) => queue,
+      Err(answer) => return answer,
+    };
+    let now = time::get_time().sec;
+    let due = match record.get_due(now) {
+      Ok(due) => due,
+      Err(_) => {
+        warn!("Publish of client {} has delay and time", connection_data.get_cuid());
+        return rejected_answer(
+          ErrorCodeEnum::BadRequest, TASK_REQUEST_ERROR_TMP.to_string(), connection_data);
       }
-      index += 1;
+    };
+    let method = record.get_method();
+    match method {
+      Some(ref method) if !state.has_capable_server(method) => {
+        let msg = format!("{} {}", NO_CAPABLE_SERVER_TMP, method);
+        return rejected_answer(ErrorCodeEnum::NoCapableServer, msg, connection_data);
+      },
+      _ => {}
+    }
+    // expiry notice goes to producer by cuid
+    let mut new_task = NewTask::new(record.get_data(), connection_data.get_cuid());
+    new_task.priority = record.get_priority();
+    new_task.ttl = record.get_ttl();
+    new_task.notify = record.get_notify();
+    new_task.method = method;
+    new_task.blob = connection_data.get_blob();
+    let published = match due {
+      Some(due) if due > now => state.schedule(queue, new_task, due),
+      _ => state.publish(queue, new_task),
+    };
+    match published {
+      Some(id) => {
+        debug!("Client {} published task {} to '{}'", connection_data.get_cuid(), id, queue.name);
+        (AnswerTargetEnum::Published.to_u32(), id.to_string())
+      },
+      None => {
+        let msg = format!("{} {}", QUEUE_FULL_TMP, queue.name);
+        rejected_answer(ErrorCodeEnum::Overloaded, msg, connection_data)
+      }
+    }
+  }
+
+  fn answer_consume(
+      client_data: &String,
+      connection_data: &mut ClientConnectionData,
+      options: &ProjectOptions,
+      state: &BrokerState) -> (u32, String) {
+    // servers consume tasks, task comes to server as answer by cuid
+    if connection_data.get_group() != ClientGroupEnum::Server.to_u32() {
+      return answer_forbidden(client_data, connection_data, options, state);
     }
-    key_line.push_str(&connection_data.get_temp_data_as_string());
+    let record: ConsumeRequest = match json::decode(client_data) {
+      Ok(record) => record,
+      Err(err) => {
+        warn!("Consume data of client {} error: {}", connection_data.get_cuid(), err);
+        return rejected_answer(
+          ErrorCodeEnum::BadRequest, TASK_REQUEST_ERROR_TMP.to_string(), connection_data);
+      }
+    };
+    let queue = match allowed_queue(&record.get_queue(), connection_data, options) {
+      Ok(queue) => queue,
+      Err(answer) => return answer,
+    };
+    state.consume(queue, connection_data.get_cuid());
+    (AnswerTargetEnum::Skip.to_u32(), String::new())
+  }
 
-    for ch in secret {
-      key_line.push(ch);
+  // Ack (done) or Nack (return to queue) of server for task in flight
+  fn answer_task_result(
+      client_data: &String,
+      connection_data: &mut ClientConnectionData,
+      options: &ProjectOptions,
+      state: &BrokerState,
+      done: bool) -> (u32, String) {
+    if connection_data.get_group() != ClientGroupEnum::Server.to_u32() {
+      return answer_forbidden(client_data, connection_data, options, state);
+    }
+    let record: TaskRequest = match json::decode(client_data) {
+      Ok(record) => record,
+      Err(err) => {
+        warn!("Task result of client {} error: {}", connection_data.get_cuid(), err);
+        return rejected_answer(
+          ErrorCodeEnum::BadRequest, TASK_REQUEST_ERROR_TMP.to_string(), connection_data);
+      }
+    };
+    let id = record.get_id();
+    let cuid = connection_data.get_cuid();
+    let found = if done {
+      state.ack(id, &cuid)
+    } else {
+      state.nack(id, &cuid, &record.get_reason(), options)
+    };
+    if found {
+      (AnswerTargetEnum::Done.to_u32(), id.to_string())
+    } else {
+      // task is returned to queue by timeout or it is not task of client
+      let msg = format!("{} {}", TASK_NOT_FOUND_TMP, id);
+      rejected_answer(ErrorCodeEnum::TaskNotFound, msg, connection_data)
     }
-    hasher.input_str(&key_line);
-    let hex = hasher.result_str().to_string();
+  }
+
+  fn answer_ack(
+      client_data: &String,
+      connection_data: &mut ClientConnectionData,
+      options: &ProjectOptions,
+      state: &BrokerState) -> (u32, String) {
+    answer_task_result(client_data, connection_data, options, state, true)
+  }
+
+  fn answer_nack(
+      client_data: &String,
+      connection_data: &mut ClientConnectionData,
+      options: &ProjectOptions,
+      state: &BrokerState) -> (u32, String) {
+    answer_task_result(client_data, connection_data, options, state, false)
+  }
 
-    if hex == client_hex {
-      (AnswerTargetEnum::WhoAreYou.to_u32(), "OK".to_string())
+  fn answer_result(
+      client_data: &String,
+      connection_data: &mut ClientConnectionData,
+      options: &ProjectOptions,
+      state: &BrokerState) -> (u32, String) {
+    // result goes to service by cuid of task, task is done
+    if connection_data.get_group() != ClientGroupEnum::Server.to_u32() {
+      return answer_forbidden(client_data, connection_data, options, state);
+    }
+    let record: ResultRequest = match json::decode(client_data) {
+      Ok(record) => record,
+      Err(err) => {
+        warn!("Result data of client {} error: {}", connection_data.get_cuid(), err);
+        return rejected_answer(
+          ErrorCodeEnum::BadRequest, TASK_REQUEST_ERROR_TMP.to_string(), connection_data);
+      }
+    };
+    let id = record.get_id();
+    let cuid = connection_data.get_cuid();
+    if state.result(id, &cuid, record.get_data(), connection_data.get_blob()) {
+      (AnswerTargetEnum::Done.to_u32(), id.to_string())
     } else {
-      let msg = format!("{} {}", AUTH_FAILED_TMP, "Check secret key?").to_string();
-      (AnswerTargetEnum::Error.to_u32(), msg)
+      let msg = format!("{} {}", TASK_NOT_FOUND_TMP, id);
+      rejected_answer(ErrorCodeEnum::TaskNotFound, msg, connection_data)
     }
   }
 
+  // topic of request, rejected answer if topic is empty
+  fn topic_request(
+      client_data: &String,
+      connection_data: &ClientConnectionData) -> Result<TopicRequest, (u32, String)> {
+    match json::decode::<TopicRequest>(client_data) {
+      Ok(ref record) if record.get_topic().is_empty() => {
+        warn!("Topic of client {} is empty", connection_data.get_cuid());
+        Err(rejected_answer(
+          ErrorCodeEnum::BadRequest, TOPIC_REQUEST_ERROR_TMP.to_string(), connection_data))
+      },
+      Ok(record) => Ok(record),
+      Err(err) => {
+        warn!("Topic data of client {} error: {}", connection_data.get_cuid(), err);
+        Err(rejected_answer(
+          ErrorCodeEnum::BadRequest, TOPIC_REQUEST_ERROR_TMP.to_string(), connection_data))
+      }
+    }
+  }
+
+  fn answer_subscribe(
+      client_data: &String,
+      connection_data: &mut ClientConnectionData,
+      options: &ProjectOptions,
+      state: &BrokerState) -> (u32, String) {
+    // events of topic come to client as answers by cuid until Unsubscribe or close
+    let topic = match topic_request(client_data, connection_data) {
+      Ok(record) => record.get_topic(),
+      Err(answer) => return answer,
+    };
+    if state.subscribe(&topic, &connection_data.get_cuid()) {
+      debug!("Client {} subscribed to '{}'", connection_data.get_cuid(), topic);
+    }
+    (AnswerTargetEnum::Done.to_u32(), topic)
+  }
+
+  fn answer_unsubscribe(
+      client_data: &String,
+      connection_data: &mut ClientConnectionData,
+      options: &ProjectOptions,
+      state: &BrokerState) -> (u32, String) {
+    let topic = match topic_request(client_data, connection_data) {
+      Ok(record) => record.get_topic(),
+      Err(answer) => return answer,
+    };
+    if state.unsubscribe(&topic, &connection_data.get_cuid()) {
+      (AnswerTargetEnum::Done.to_u32(), topic)
+    } else {
+      let msg = format!("{} {}", NOT_SUBSCRIBED_TMP, topic);
+      rejected_answer(ErrorCodeEnum::NotSubscribed, msg, connection_data)
+    }
+  }
+
+  fn answer_publish_topic(
+      client_data: &String,
+      connection_data: &mut ClientConnectionData,
+      options: &ProjectOptions,
+      state: &BrokerState) -> (u32, String) {
+    // answer data is number of subscribers which take event
+    let record = match topic_request(client_data, connection_data) {
+      Ok(record) => record,
+      Err(answer) => return answer,
+    };
+    let count = state.publish_topic(
+      &record.get_topic(), record.get_data(), connection_data.get_blob());
+    (AnswerTargetEnum::Done.to_u32(), count.to_string())
+  }
+
+  fn answer_scheduled_list(
+      client_data: &String,
+      connection_data: &mut ClientConnectionData,
+      options: &ProjectOptions,
+      state: &BrokerState) -> (u32, String) {
+    // data is name of queue, empty data - all queues
+    if connection_data.get_group() != ClientGroupEnum::Manager.to_u32() {
+      return answer_forbidden(client_data, connection_data, options, state);
+    }
+    let scheduled = state.get_scheduled(client_data.trim());
+    (AnswerTargetEnum::ScheduledList.to_u32(), json::encode(&scheduled).unwrap())
+  }
+
+  fn answer_cancel_scheduled(
+      client_data: &String,
+      connection_data: &mut ClientConnectionData,
+      options: &ProjectOptions,
+      state: &BrokerState) -> (u32, String) {
+    if connection_data.get_group() != ClientGroupEnum::Manager.to_u32() {
+      return answer_forbidden(client_data, connection_data, options, state);
+    }
+    let record: TaskRequest = match json::decode(client_data) {
+      Ok(record) => record,
+      Err(err) => {
+        warn!("Cancel data of client {} error: {}", connection_data.get_cuid(), err);
+        return rejected_answer(
+          ErrorCodeEnum::BadRequest, TASK_REQUEST_ERROR_TMP.to_string(), connection_data);
+      }
+    };
+    let id = record.get_id();
+    if state.cancel_scheduled(id) {
+      info!("Manager {} canceled scheduled task {}", connection_data.get_cuid(), id);
+      (AnswerTargetEnum::Done.to_u32(), id.to_string())
+    } else {
+      let msg = format!("{} {}", SCHEDULED_NOT_FOUND_TMP, id);
+      rejected_answer(ErrorCodeEnum::TaskNotFound, msg, connection_data)
+    }
+  }
+
+  fn answer_dead_list(
+      client_data: &String,
+      connection_data: &mut ClientConnectionData,
+      options: &ProjectOptions,
+      state: &BrokerState) -> (u32, String) {
+    // data is name of dead letter queue
+    if connection_data.get_group() != ClientGroupEnum::Manager.to_u32() {
+      return answer_forbidden(client_data, connection_data, options, state);
+    }
+    let dead = state.get_dead(client_data.trim());
+    (AnswerTargetEnum::DeadList.to_u32(), json::encode(&dead).unwrap())
+  }
+
+  // requeue or purge of dead letters
+  fn answer_dead_tasks(
+      client_data: &String,
+      connection_data: &mut ClientConnectionData,
+      options: &ProjectOptions,
+      state: &BrokerState,
+      requeue: bool) -> (u32, String) {
+    if connection_data.get_group() != ClientGroupEnum::Manager.to_u32() {
+      return answer_forbidden(client_data, connection_data, options, state);
+    }
+    let record: DeadRequest = match json::decode(client_data) {
+      Ok(record) => record,
+      Err(err) => {
+        warn!("Dead letters data of client {} error: {}", connection_data.get_cuid(), err);
+        return rejected_answer(
+          ErrorCodeEnum::BadRequest, TASK_REQUEST_ERROR_TMP.to_string(), connection_data);
+      }
+    };
+    let name = record.get_queue();
+    let count = if requeue {
+      state.requeue_dead(&name, record.get_id(), options)
+    } else {
+      state.purge_dead(&name, record.get_id())
+    };
+    info!(
+      "Manager {} {} {} dead tasks of '{}'",
+      connection_data.get_cuid(), if requeue { "requeued" } else { "purged" }, count, name);
+    (AnswerTargetEnum::Done.to_u32(), count.to_string())
+  }
+
+  fn answer_requeue_dead(
+      client_data: &String,
+      connection_data: &mut ClientConnectionData,
+      options: &ProjectOptions,
+      state: &BrokerState) -> (u32, String) {
+    answer_dead_tasks(client_data, connection_data, options, state, true)
+  }
+
+  fn answer_purge_dead(
+      client_data: &String,
+      connection_data: &mut ClientConnectionData,
+      options: &ProjectOptions,
+      state: &BrokerState) -> (u32, String) {
+    answer_dead_tasks(client_data, connection_data, options, state, false)
+  }
+
   pub fn take_client_data(
       client_data: &String,
       connection_data: &mut ClientConnectionData,
-      options: &ProjectOptions) -> (u32, String) {
+      options: &ProjectOptions,
+      state: &BrokerState) -> (u32, String) {
     // data is some json
     let answer_code: u32;
+    let mut answer_data = String::new();
     let json_record: Option<ClientDescription> = match(json::decode(client_data)) {
       Ok(record) => Some(record),
       Err(err) => {
@@ -91,44 +727,704 @@ pub mod exec {
         None
       },
     };
+    // group of client with identity is fixed by credentials, shared secret is for services
+    if connection_data.get_identity().is_empty() {
+      connection_data.set_group(ClientGroupEnum::Service.to_u32());
+    }
     match json_record {
+      Some(ref record) if record.get_group() != connection_data.get_group() => {
+        let msg = format!("{} {}", CLIENT_GROUP_ERROR_TMP, record.get_group());
+        let (code, data) = error_answer(ErrorCodeEnum::BadClientData, msg, connection_data);
+        answer_code = code;
+        answer_data = data;
+      },
       Some(record) => {
         match record.get_cid() {
           //client back
           Some(cid) => {
-            // client has cuid, save it
-            connection_data.set_cuid(cid);
-            answer_code = AnswerTargetEnum::Wait.to_u32();
+            if check_resume_token(&record.get_token(), &cid, connection_data, options, state) {
+              // client has cuid, save it
+              state.client_resumed(&cid);
+              connection_data.set_cuid(cid);
+              answer_code = AnswerTargetEnum::Wait.to_u32();
+              answer_data = create_resume_token(connection_data, options, state);
+            } else {
+              warn!("Client {} can't resume session of {}", connection_data.get_cuid(), cid);
+              let (code, data) = error_answer(
+                ErrorCodeEnum::InvalidToken, RESUME_TOKEN_ERROR_TMP.to_string(), connection_data);
+              answer_code = code;
+              answer_data = data;
+            }
           },
           None => {
-            // client take cuid
+            // client take cuid and token for resume
             answer_code = AnswerTargetEnum::TakeCuid.to_u32();
+            answer_data = create_resume_token(connection_data, options, state);
           }
         }
+        // methods of server for routing of tasks
+        if answer_code != AnswerTargetEnum::Error.to_u32() &&
+            connection_data.get_group() == ClientGroupEnum::Server.to_u32() {
+          state.server_connected(&connection_data.get_cuid(), record.get_methods());
+        }
       },
       None => {
-        answer_code = AnswerTargetEnum::Error.to_u32();
+        let (code, data) = error_answer(
+          ErrorCodeEnum::BadClientData, CLIENT_DATA_ERROR_TMP.to_string(), connection_data);
+        answer_code = code;
+        answer_data = data;
       }
     }
-    (answer_code, String::new())
+    (answer_code, answer_data)
   }
 
   // === iface ===
-  pub fn get_answer_method(target: CommandTargetEnum) ->
-      Box<Fn(&String, &mut ClientConnectionData, &ProjectOptions) -> (u32, String)> {
-    // data creator for answer
+  pub fn get_answer_method(target: CommandTargetEnum, version: u32) ->
+      Box<Fn(&String, &mut ClientConnectionData, &ProjectOptions, &BrokerState) -> (u32, String)> {
+    // data creator for answer, version of protocol from SigIn
     match target {
-      CommandTargetEnum::Unknown => Box::new(answer_empty),
+      CommandTargetEnum::Unknown => if version > LEGACY_PROTOCOL_VERSION {
+        Box::new(answer_unknown_target)
+      } else {
+        Box::new(answer_empty)
+      },
       CommandTargetEnum::Quit => Box::new(client_fast_quit_rquest),
       CommandTargetEnum::SigIn => Box::new(answer_verification_request),
       CommandTargetEnum::Auth => Box::new(answer_check_auth),
       CommandTargetEnum::ClientData => Box::new(take_client_data),
+      CommandTargetEnum::Ping => Box::new(answer_pong),
+      CommandTargetEnum::ListBans => Box::new(answer_ban_list),
+      CommandTargetEnum::ClearBans => Box::new(answer_clear_bans),
+      CommandTargetEnum::Publish => Box::new(answer_publish),
+      CommandTargetEnum::Consume => Box::new(answer_consume),
+      CommandTargetEnum::Ack => Box::new(answer_ack),
+      CommandTargetEnum::Nack => Box::new(answer_nack),
+      CommandTargetEnum::ListScheduled => Box::new(answer_scheduled_list),
+      CommandTargetEnum::CancelScheduled => Box::new(answer_cancel_scheduled),
+      CommandTargetEnum::ListDead => Box::new(answer_dead_list),
+      CommandTargetEnum::RequeueDead => Box::new(answer_requeue_dead),
+      CommandTargetEnum::PurgeDead => Box::new(answer_purge_dead),
+      CommandTargetEnum::Result => Box::new(answer_result),
+      CommandTargetEnum::Subscribe => Box::new(answer_subscribe),
+      CommandTargetEnum::Unsubscribe => Box::new(answer_unsubscribe),
+      CommandTargetEnum::PublishTopic => Box::new(answer_publish_topic),
+    }
+  }
+  // method by acl of client group or identity, commands of session always allowed
+  pub fn get_allowed_answer_method(
+      target: u32,
+      connection_data: &ClientConnectionData,
+      options: &ProjectOptions) ->
+      Box<Fn(&String, &mut ClientConnectionData, &ProjectOptions, &BrokerState) -> (u32, String)> {
+    let allowed = match target.as_target_enum() {
+      CommandTargetEnum::Quit | CommandTargetEnum::SigIn | CommandTargetEnum::Auth |
+      CommandTargetEnum::ClientData | CommandTargetEnum::Ping => true,
+      _ => options.allows_target(
+        &connection_data.get_identity(), connection_data.get_group(), target),
+    };
+    if allowed {
+      get_answer_method(target.as_target_enum(), connection_data.get_version())
+    } else {
+      warn!(
+        "Command {} is forbidden for client {} (group {})",
+        target, connection_data.get_cuid(), connection_data.get_group());
+      Box::new(answer_forbidden)
     }
   }
   // === ===
   impl CommandHandle for Command {
-    fn execute(&mut self, options: &ProjectOptions, connection_data: &mut ClientConnectionData) -> Answer {
-      self.get_answer(&options, connection_data)
+    fn execute(
+        &mut self,
+        options: &ProjectOptions,
+        connection_data: &mut ClientConnectionData,
+        state: &BrokerState) -> Answer {
+      self.get_answer(&options, connection_data, state)
     }
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use crypto::digest::Digest;
+  use crypto::hmac::Hmac;
+  use crypto::mac::Mac;
+  use crypto::sha1::Sha1;
+  use crypto::sha2::Sha256;
+  use handler::exec::{get_answer_method, get_allowed_answer_method};
+  use options::configuration::{
+    ProjectOptions, ClientCredentials, AclRule, SharedSecret, QueueOptions};
+  use protocol::{CommandTargetEnum, AnswerTargetEnum, ClientGroupEnum, TargetAsDigit};
+  use queue::NewTask;
+  use rustc_serialize::json::Json;
+  use state::BrokerState;
+  use transport::{ClientConnectionData, CuidSource};
+
+  fn hmac_request(secret: &str, key: &str) -> String {
+    let nonce = "client-nonce-0123456789";
+    let mut hmac = Hmac::new(Sha256::new(), secret.as_bytes());
+    hmac.input(key.as_bytes());
+    hmac.input(nonce.as_bytes());
+    let hex: String = hmac.result().code().iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!("{{\"nonce\": \"{}\", \"hmac\": \"{}\"}}", nonce, hex)
+  }
+
+  #[test]
+  fn test_sigin_version_negotiation() {
+    let options = ProjectOptions::new();
+    let state = BrokerState::new();
+    let sigin = get_answer_method(CommandTargetEnum::SigIn, 1);
+
+    let mut legacy_data = ClientConnectionData::new();
+    let (target, key) = sigin(&String::new(), &mut legacy_data, &options, &state);
+    assert_eq!(target, AnswerTargetEnum::VerificationRequest.to_u32());
+    assert_eq!(key, legacy_data.get_temp_data_as_string());
+    assert_eq!(legacy_data.get_version(), 1);
+
+    let mut connection_data = ClientConnectionData::new();
+    let request = "{\"versions\": [1, 2, 3]}".to_string();
+    let (target, answer) = sigin(&request, &mut connection_data, &options, &state);
+    assert_eq!(target, AnswerTargetEnum::VerificationRequest.to_u32());
+    assert!(answer.contains("\"version\":2"));
+    assert_eq!(connection_data.get_version(), 2);
+    assert_eq!(connection_data.get_heartbeat(), options.heartbeat_interval);
+
+    let mut new_data = ClientConnectionData::new();
+    let request = "{\"versions\": [7]}".to_string();
+    let (target, _) = sigin(&request, &mut new_data, &options, &state);
+    assert_eq!(target, AnswerTargetEnum::Error.to_u32());
+  }
+
+  #[test]
+  fn test_error_answer_by_version() {
+    let options = ProjectOptions::new();
+    let state = BrokerState::new();
+    let client_data = get_answer_method(CommandTargetEnum::ClientData, 2);
+    let mut connection_data = ClientConnectionData::new();
+
+    let (target, message) = client_data(&"-".to_string(), &mut connection_data, &options, &state);
+    assert_eq!(target, AnswerTargetEnum::Error.to_u32());
+    assert!(!message.starts_with("{"));
+
+    connection_data.set_version(2);
+    let (target, data) = client_data(&"-".to_string(), &mut connection_data, &options, &state);
+    assert_eq!(target, AnswerTargetEnum::Error.to_u32());
+    assert!(data.starts_with("{\"code\":10,\"error\":\"bad_client_data\""));
+  }
+
+  #[test]
+  fn test_hmac_auth() {
+    let mut options = ProjectOptions::new();
+    let state = BrokerState::new();
+    options.secret = "1234567890".to_string();
+    let sigin = get_answer_method(CommandTargetEnum::SigIn, 1);
+    let auth = get_answer_method(CommandTargetEnum::Auth, 1);
+
+    let mut connection_data = ClientConnectionData::new();
+    let (_, key) = sigin(&String::new(), &mut connection_data, &options, &state);
+    let request = hmac_request(&options.secret, &key);
+    let (target, _) = auth(&request, &mut connection_data, &options, &state);
+    assert_eq!(target, AnswerTargetEnum::WhoAreYou.to_u32());
+    // key is used
+    let (target, _) = auth(&request, &mut connection_data, &options, &state);
+    assert_eq!(target, AnswerTargetEnum::Error.to_u32());
+    // identity can't be changed after auth
+    let request = "{\"versions\": [2], \"identity\": \"manager1\"}".to_string();
+    let (target, _) = sigin(&request, &mut connection_data, &options, &state);
+    assert_eq!(target, AnswerTargetEnum::Error.to_u32());
+    assert!(connection_data.get_identity().is_empty());
+
+    // sha1 answer of old client
+    let mut connection_data = ClientConnectionData::new();
+    let (_, key) = sigin(&String::new(), &mut connection_data, &options, &state);
+    let client_key: String = (0..128).map(|_| 'k').collect();
+    let mut hasher = Sha1::new();
+    hasher.input_str(&format!("{}{}{}", client_key, key, options.secret));
+    let request = format!("{}{}", client_key, hasher.result_str());
+    let (target, _) = auth(&request, &mut connection_data, &options, &state);
+    assert_eq!(target, AnswerTargetEnum::Error.to_u32());
+
+    options.legacy_auth = true;
+    sigin(&String::new(), &mut connection_data, &options, &state);
+    let (target, _) = auth(&request, &mut connection_data, &options, &state);
+    assert_eq!(target, AnswerTargetEnum::Error.to_u32());
+    let (_, key) = sigin(&String::new(), &mut connection_data, &options, &state);
+    let mut hasher = Sha1::new();
+    hasher.input_str(&format!("{}{}{}", client_key, key, options.secret));
+    let request = format!("{}{}", client_key, hasher.result_str());
+    let (target, _) = auth(&request, &mut connection_data, &options, &state);
+    assert_eq!(target, AnswerTargetEnum::WhoAreYou.to_u32());
+  }
+
+  #[test]
+  fn test_client_credentials() {
+    let mut options = ProjectOptions::new();
+    let state = BrokerState::new();
+    options.secret = "1234567890".to_string();
+    options.credentials.push(ClientCredentials {
+      identity: "manager1".to_string(),
+      secret: "abc".to_string(),
+      group: ClientGroupEnum::Manager.to_u32(),
+    });
+    let sigin = get_answer_method(CommandTargetEnum::SigIn, 1);
+    let auth = get_answer_method(CommandTargetEnum::Auth, 1);
+    let client_data = get_answer_method(CommandTargetEnum::ClientData, 1);
+    let request = "{\"versions\": [2], \"identity\": \"manager1\"}".to_string();
+
+    // shared secret is not secret of identity
+    let mut connection_data = ClientConnectionData::new();
+    let (_, answer) = sigin(&request, &mut connection_data, &options, &state);
+    let key = Json::from_str(&answer).unwrap()["key"].as_string().unwrap().to_string();
+    let (target, _) = auth(&hmac_request("1234567890", &key), &mut connection_data, &options, &state);
+    assert_eq!(target, AnswerTargetEnum::Error.to_u32());
+
+    let mut connection_data = ClientConnectionData::new();
+    let (_, answer) = sigin(&request, &mut connection_data, &options, &state);
+    let key = Json::from_str(&answer).unwrap()["key"].as_string().unwrap().to_string();
+    let (target, _) = auth(&hmac_request("abc", &key), &mut connection_data, &options, &state);
+    assert_eq!(target, AnswerTargetEnum::WhoAreYou.to_u32());
+    assert_eq!(connection_data.get_group(), ClientGroupEnum::Manager.to_u32());
+    let (target, _) = client_data(
+      &"{\"group\": 2, \"cid\": \"\"}".to_string(), &mut connection_data, &options, &state);
+    assert_eq!(target, AnswerTargetEnum::Error.to_u32());
+    let (target, _) = client_data(
+      &"{\"group\": 1, \"cid\": \"\"}".to_string(), &mut connection_data, &options, &state);
+    assert_eq!(target, AnswerTargetEnum::TakeCuid.to_u32());
+  }
+
+  #[test]
+  fn test_forbidden_target() {
+    let mut options = ProjectOptions::new();
+    let state = BrokerState::new();
+    options.acl.push(AclRule {
+      group: Some(ClientGroupEnum::Service.to_u32()),
+      identity: None,
+      targets: Some(Vec::new()),
+      queues: None,
+    });
+    let mut connection_data = ClientConnectionData::new();
+    connection_data.set_version(2);
+    let unknown = CommandTargetEnum::Unknown.to_u32();
+    let method = get_allowed_answer_method(unknown, &connection_data, &options);
+    let (target, data) = method(&String::new(), &mut connection_data, &options, &state);
+    assert_eq!(target, AnswerTargetEnum::Forbidden.to_u32());
+    assert!(data.starts_with("{\"code\":13,\"error\":\"forbidden\""));
+
+    let ping = CommandTargetEnum::Ping.to_u32();
+    let method = get_allowed_answer_method(ping, &connection_data, &options);
+    let (target, _) = method(&String::new(), &mut connection_data, &options, &state);
+    assert_eq!(target, AnswerTargetEnum::Pong.to_u32());
+
+    connection_data.set_group(ClientGroupEnum::Manager.to_u32());
+    let method = get_allowed_answer_method(unknown, &connection_data, &options);
+    let (target, _) = method(&String::new(), &mut connection_data, &options, &state);
+    assert_eq!(target, AnswerTargetEnum::Error.to_u32());
+  }
+
+  #[test]
+  fn test_auth_ban() {
+    let mut options = ProjectOptions::new();
+    let state = BrokerState::new();
+    options.secret = "1234567890".to_string();
+    options.auth_max_failures = 2;
+    let sigin = get_answer_method(CommandTargetEnum::SigIn, 1);
+    let auth = get_answer_method(CommandTargetEnum::Auth, 1);
+    let list_bans = get_answer_method(CommandTargetEnum::ListBans, 1);
+    let clear_bans = get_answer_method(CommandTargetEnum::ClearBans, 1);
+    let mut connection_data = ClientConnectionData::new();
+    connection_data.set_address("10.0.0.1".to_string());
+
+    for _ in 0..2 {
+      let (_, key) = sigin(&String::new(), &mut connection_data, &options, &state);
+      let (target, _) = auth(&hmac_request("-", &key), &mut connection_data, &options, &state);
+      assert_eq!(target, AnswerTargetEnum::Error.to_u32());
+    }
+    let (_, key) = sigin(&String::new(), &mut connection_data, &options, &state);
+    let request = hmac_request(&options.secret, &key);
+    let (target, message) = auth(&request, &mut connection_data, &options, &state);
+    assert_eq!(target, AnswerTargetEnum::Error.to_u32());
+    assert!(message.starts_with("Too many auth failures"));
+
+    let mut manager_data = ClientConnectionData::new();
+    let (target, _) = list_bans(&String::new(), &mut manager_data, &options, &state);
+    assert_eq!(target, AnswerTargetEnum::Forbidden.to_u32());
+    manager_data.set_group(ClientGroupEnum::Manager.to_u32());
+    let (target, _) = list_bans(&String::new(), &mut manager_data, &options, &state);
+    assert_eq!(target, AnswerTargetEnum::Forbidden.to_u32());
+    options.credentials.push(ClientCredentials {
+      identity: "manager1".to_string(),
+      secret: "abc".to_string(),
+      group: ClientGroupEnum::Manager.to_u32(),
+    });
+    manager_data.set_identity("manager1".to_string());
+    let (target, bans) = list_bans(&String::new(), &mut manager_data, &options, &state);
+    assert_eq!(target, AnswerTargetEnum::BanList.to_u32());
+    assert!(bans.contains("\"name\":\"10.0.0.1\""));
+    let (target, count) = clear_bans(&"10.0.0.1".to_string(), &mut manager_data, &options, &state);
+    assert_eq!(target, AnswerTargetEnum::Done.to_u32());
+    assert_eq!(count, "1");
+
+    let (_, key) = sigin(&String::new(), &mut connection_data, &options, &state);
+    let request = hmac_request(&options.secret, &key);
+    let (target, _) = auth(&request, &mut connection_data, &options, &state);
+    assert_eq!(target, AnswerTargetEnum::WhoAreYou.to_u32());
+  }
+
+  #[test]
+  fn test_secret_rotation() {
+    let mut options = ProjectOptions::new();
+    let state = BrokerState::new();
+    options.secrets.push(SharedSecret {
+      name: "expired".to_string(),
+      value: "old".to_string(),
+      expires: Some(1000),
+    });
+    options.secrets.push(SharedSecret {
+      name: "next".to_string(),
+      value: "new".to_string(),
+      expires: None,
+    });
+    let sigin = get_answer_method(CommandTargetEnum::SigIn, 1);
+    let auth = get_answer_method(CommandTargetEnum::Auth, 1);
+    let mut connection_data = ClientConnectionData::new();
+
+    let (_, key) = sigin(&String::new(), &mut connection_data, &options, &state);
+    let (target, _) = auth(&hmac_request("new", &key), &mut connection_data, &options, &state);
+    assert_eq!(target, AnswerTargetEnum::WhoAreYou.to_u32());
+    let (_, key) = sigin(&String::new(), &mut connection_data, &options, &state);
+    let (target, _) = auth(&hmac_request("old", &key), &mut connection_data, &options, &state);
+    assert_eq!(target, AnswerTargetEnum::Error.to_u32());
+  }
+
+  #[test]
+  fn test_resume_token() {
+    let options = ProjectOptions::new();
+    let state = BrokerState::new();
+    let client_data = get_answer_method(CommandTargetEnum::ClientData, 1);
+    let mut connection_data = ClientConnectionData::new();
+    connection_data.set_cuid("n1-10.0.0.1:1000-1".to_string());
+    // shared secret is not for managers and servers
+    for group in [1, 2].iter() {
+      let request = format!("{{\"group\": {}, \"cid\": \"\"}}", group);
+      let (target, _) = client_data(&request, &mut connection_data, &options, &state);
+      assert_eq!(target, AnswerTargetEnum::Error.to_u32());
+    }
+    let (target, token) = client_data(
+      &"{\"group\": 3, \"cid\": \"\"}".to_string(), &mut connection_data, &options, &state);
+    assert_eq!(target, AnswerTargetEnum::TakeCuid.to_u32());
+
+    let resume = |cid: &str, group: u32, token: &str| {
+      let mut new_data = ClientConnectionData::new();
+      new_data.set_cuid("n1-10.0.0.2:2000-2".to_string());
+      let request = format!("{{\"group\": {}, \"cid\": \"{}\", \"token\": \"{}\"}}", group, cid, token);
+      let (target, _) = client_data(&request, &mut new_data, &options, &state);
+      (target, new_data.get_cuid())
+    };
+    assert_eq!(
+      resume("n1-10.0.0.1:1000-1", 3, &token),
+      (AnswerTargetEnum::Wait.to_u32(), "n1-10.0.0.1:1000-1".to_string()));
+    assert_eq!(resume("n1-10.0.0.1:1000-1", 1, &token).0, AnswerTargetEnum::Error.to_u32());
+    assert_eq!(resume("n1-10.0.0.3:3000-3", 3, &token).0, AnswerTargetEnum::Error.to_u32());
+    assert_eq!(resume("n1-10.0.0.1:1000-1", 3, "").0, AnswerTargetEnum::Error.to_u32());
+  }
+
+  #[test]
+  fn test_queue_tasks() {
+    let mut options = ProjectOptions::new();
+    let state = BrokerState::new();
+    let mut queue = QueueOptions::new("mail");
+    queue.max_size = Some(1);
+    options.queues.push(queue);
+    options.acl.push(AclRule {
+      group: None,
+      identity: Some("service2".to_string()),
+      targets: None,
+      queues: Some(vec!["other".to_string()]),
+    });
+    let publish = get_answer_method(CommandTargetEnum::Publish, 2);
+    let consume = get_answer_method(CommandTargetEnum::Consume, 2);
+    let mut service_data = ClientConnectionData::new();
+    service_data.set_cuid("service".to_string());
+    service_data.set_group(ClientGroupEnum::Service.to_u32());
+    service_data.set_version(2);
+    let mut server_data = ClientConnectionData::new();
+    server_data.set_cuid("server".to_string());
+    server_data.set_group(ClientGroupEnum::Server.to_u32());
+    let task = "{\"queue\": \"mail\", \"data\": \"hello\"}".to_string();
+
+    let (target, _) = publish(&task, &mut server_data, &options, &state);
+    assert_eq!(target, AnswerTargetEnum::Forbidden.to_u32());
+    let (target, id) = publish(&task, &mut service_data, &options, &state);
+    assert_eq!(target, AnswerTargetEnum::Published.to_u32());
+    assert_eq!(id, "1");
+    let (target, data) = publish(&task, &mut service_data, &options, &state);
+    assert_eq!(target, AnswerTargetEnum::Rejected.to_u32());
+    assert!(data.starts_with("{\"code\":5,"));
+    let (target, data) = publish(
+      &"{\"queue\": \"nothing\", \"data\": \"\"}".to_string(), &mut service_data, &options, &state);
+    assert_eq!(target, AnswerTargetEnum::Rejected.to_u32());
+    assert!(data.starts_with("{\"code\":6,"));
+    service_data.set_identity("service2".to_string());
+    let (target, _) = publish(&task, &mut service_data, &options, &state);
+    assert_eq!(target, AnswerTargetEnum::Forbidden.to_u32());
+
+    let request = "{\"queue\": \"mail\"}".to_string();
+    let (target, _) = consume(&request, &mut server_data, &options, &state);
+    assert_eq!(target, AnswerTargetEnum::Skip.to_u32());
+    let routed = state.take_routed();
+    assert_eq!(routed.len(), 1);
+    assert_eq!(routed[0].get_cuid(), "server");
+    assert_eq!(routed[0].to_u32(), AnswerTargetEnum::Task.to_u32());
+    // next task goes to waiting server
+    consume(&request, &mut server_data, &options, &state);
+    assert!(state.take_routed().is_empty());
+    service_data.set_identity(String::new());
+    publish(&task, &mut service_data, &options, &state);
+    assert_eq!(state.take_routed().len(), 1);
+  }
+
+  #[test]
+  fn test_task_ack() {
+    let mut options = ProjectOptions::new();
+    let state = BrokerState::new();
+    options.queues.push(QueueOptions::new("mail"));
+    let ack = get_answer_method(CommandTargetEnum::Ack, 2);
+    let nack = get_answer_method(CommandTargetEnum::Nack, 2);
+    let queue = options.get_queue("mail").unwrap().clone();
+    state.publish(&queue, NewTask::new("a".to_string(), "service".to_string()));
+    state.consume(&queue, "server1".to_string());
+    assert_eq!(state.take_routed().len(), 1);
+    let mut server_data = ClientConnectionData::new();
+    server_data.set_cuid("server2".to_string());
+    server_data.set_group(ClientGroupEnum::Server.to_u32());
+    server_data.set_version(2);
+    let request = "{\"id\": 1}".to_string();
+
+    // task of other server
+    let (target, data) = ack(&request, &mut server_data, &options, &state);
+    assert_eq!(target, AnswerTargetEnum::Rejected.to_u32());
+    assert!(data.starts_with("{\"code\":17,"));
+    // second delivery after Nack
+    state.consume(&queue, "server2".to_string());
+    let mut first_data = server_data.clone();
+    first_data.set_cuid("server1".to_string());
+    let (target, _) = nack(&request, &mut first_data, &options, &state);
+    assert_eq!(target, AnswerTargetEnum::Done.to_u32());
+    let routed = state.take_routed();
+    assert_eq!(routed[0].get_cuid(), "server2");
+    let (target, id) = ack(&request, &mut server_data, &options, &state);
+    assert_eq!(target, AnswerTargetEnum::Done.to_u32());
+    assert_eq!(id, "1");
+    // closed client returns tasks
+    state.publish(&queue, NewTask::new("b".to_string(), "service".to_string()));
+    state.consume(&queue, "server1".to_string());
+    state.consume(&queue, "server2".to_string());
+    state.client_closed("server1", &options);
+    let routed = state.take_routed();
+    assert_eq!(routed.len(), 2);
+    assert_eq!(routed[1].get_cuid(), "server2");
+  }
+
+  #[test]
+  fn test_task_result() {
+    let mut options = ProjectOptions::new();
+    let state = BrokerState::new();
+    options.queues.push(QueueOptions::new("mail"));
+    let result = get_answer_method(CommandTargetEnum::Result, 2);
+    let queue = options.get_queue("mail").unwrap().clone();
+    let mut server_data = ClientConnectionData::new();
+    server_data.set_cuid("server".to_string());
+    server_data.set_group(ClientGroupEnum::Server.to_u32());
+    server_data.set_version(2);
+    for data in vec!["a", "b"] {
+      state.publish(&queue, NewTask::new(data.to_string(), "service".to_string()));
+      state.consume(&queue, "server".to_string());
+    }
+    assert_eq!(state.take_routed().len(), 2);
+
+    let (target, _) = result(&"{\"id\": 1, \"data\": \"ok\"}".to_string(), &mut server_data, &options, &state);
+    assert_eq!(target, AnswerTargetEnum::Done.to_u32());
+    let routed = state.take_routed();
+    assert_eq!(routed[0].get_cuid(), "service");
+    assert_eq!(routed[0].to_u32(), AnswerTargetEnum::Result.to_u32());
+    let (target, _) = result(&"{\"id\": 1, \"data\": \"ok\"}".to_string(), &mut server_data, &options, &state);
+    assert_eq!(target, AnswerTargetEnum::Rejected.to_u32());
+    // result for closed service waits resume
+    state.client_closed("service", &options);
+    result(&"{\"id\": 2, \"data\": \"ok\"}".to_string(), &mut server_data, &options, &state);
+    assert!(state.take_routed().is_empty());
+    state.client_resumed("service");
+    assert_eq!(state.take_routed().len(), 1);
+  }
+
+  #[test]
+  fn test_capable_servers() {
+    let mut options = ProjectOptions::new();
+    let state = BrokerState::new();
+    options.queues.push(QueueOptions::new("mail"));
+    let client_data = get_answer_method(CommandTargetEnum::ClientData, 2);
+    let publish = get_answer_method(CommandTargetEnum::Publish, 2);
+    let consume = get_answer_method(CommandTargetEnum::Consume, 2);
+    let mut service_data = ClientConnectionData::new();
+    service_data.set_cuid("service".to_string());
+    service_data.set_group(ClientGroupEnum::Service.to_u32());
+    service_data.set_version(2);
+    let task = "{\"queue\": \"mail\", \"data\": \"a\", \"method\": \"resize\"}".to_string();
+    let (target, data) = publish(&task, &mut service_data, &options, &state);
+    assert_eq!(target, AnswerTargetEnum::Rejected.to_u32());
+    assert!(data.starts_with("{\"code\":19,"));
+
+    let mut servers: Vec<ClientConnectionData> = Vec::new();
+    for &(cuid, methods) in [("server1", "[]"), ("server2", "[\"resize\"]")].iter() {
+      let mut server_data = ClientConnectionData::new();
+      server_data.set_cuid(cuid.to_string());
+      server_data.set_identity(cuid.to_string());
+      server_data.set_group(ClientGroupEnum::Server.to_u32());
+      server_data.set_version(2);
+      let request = format!("{{\"group\": 2, \"cid\": \"\", \"methods\": {}}}", methods);
+      client_data(&request, &mut server_data, &options, &state);
+      consume(&"{\"queue\": \"mail\"}".to_string(), &mut server_data, &options, &state);
+      servers.push(server_data);
+    }
+    let (target, _) = publish(&task, &mut service_data, &options, &state);
+    assert_eq!(target, AnswerTargetEnum::Published.to_u32());
+    let routed = state.take_routed();
+    assert_eq!(routed.len(), 1);
+    assert_eq!(routed[0].get_cuid(), "server2");
+    // repeated client data is not one more connection
+    let request = "{\"group\": 2, \"cid\": \"\", \"methods\": [\"resize\"]}".to_string();
+    client_data(&request, &mut servers[1], &options, &state);
+    // methods of closed server are removed
+    state.client_closed("server2", &options);
+    let (target, _) = publish(&task, &mut service_data, &options, &state);
+    assert_eq!(target, AnswerTargetEnum::Rejected.to_u32());
+  }
+
+  #[test]
+  fn test_topics() {
+    let options = ProjectOptions::new();
+    let state = BrokerState::new();
+    let subscribe = get_answer_method(CommandTargetEnum::Subscribe, 2);
+    let unsubscribe = get_answer_method(CommandTargetEnum::Unsubscribe, 2);
+    let publish = get_answer_method(CommandTargetEnum::PublishTopic, 2);
+    let request = "{\"topic\": \"config\"}".to_string();
+    let event = "{\"topic\": \"config\", \"data\": \"changed\"}".to_string();
+    let mut clients: Vec<ClientConnectionData> = Vec::new();
+    for cuid in vec!["server1", "server2", "service"] {
+      let mut connection_data = ClientConnectionData::new();
+      connection_data.set_cuid(cuid.to_string());
+      connection_data.set_version(2);
+      clients.push(connection_data);
+    }
+    for index in 0..2 {
+      let (target, _) = subscribe(&request, &mut clients[index], &options, &state);
+      assert_eq!(target, AnswerTargetEnum::Done.to_u32());
+    }
+    let empty = "{\"topic\": \"\"}".to_string();
+    let (target, _) = subscribe(&empty, &mut clients[2], &options, &state);
+    assert_eq!(target, AnswerTargetEnum::Rejected.to_u32());
+
+    let (target, count) = publish(&event, &mut clients[2], &options, &state);
+    assert_eq!(target, AnswerTargetEnum::Done.to_u32());
+    assert_eq!(count, "2");
+    let routed = state.take_routed();
+    assert_eq!(routed.len(), 2);
+    assert!(routed.iter().all(|answer| answer.to_u32() == AnswerTargetEnum::Event.to_u32()));
+    // subscription of closed client is removed
+    state.client_closed("server1", &options);
+    let (target, data) = unsubscribe(&request, &mut clients[0], &options, &state);
+    assert_eq!(target, AnswerTargetEnum::Rejected.to_u32());
+    assert!(data.starts_with("{\"code\":18,"));
+    let (target, _) = unsubscribe(&request, &mut clients[1], &options, &state);
+    assert_eq!(target, AnswerTargetEnum::Done.to_u32());
+    assert_eq!(publish(&event, &mut clients[2], &options, &state).1, "0");
+    assert!(state.take_routed().is_empty());
+  }
+
+  #[test]
+  fn test_scheduled_tasks() {
+    let mut options = ProjectOptions::new();
+    let state = BrokerState::new();
+    options.queues.push(QueueOptions::new("mail"));
+    let publish = get_answer_method(CommandTargetEnum::Publish, 2);
+    let list = get_answer_method(CommandTargetEnum::ListScheduled, 2);
+    let cancel = get_answer_method(CommandTargetEnum::CancelScheduled, 2);
+    let mut service_data = ClientConnectionData::new();
+    service_data.set_group(ClientGroupEnum::Service.to_u32());
+    let mut manager_data = ClientConnectionData::new();
+    manager_data.set_group(ClientGroupEnum::Manager.to_u32());
+
+    let request = "{\"queue\": \"mail\", \"data\": \"a\", \"delay\": 60, \"at\": 1}".to_string();
+    let (target, _) = publish(&request, &mut service_data, &options, &state);
+    assert_eq!(target, AnswerTargetEnum::Rejected.to_u32());
+    let request = "{\"queue\": \"mail\", \"data\": \"a\", \"delay\": 60}".to_string();
+    let (target, id) = publish(&request, &mut service_data, &options, &state);
+    assert_eq!(target, AnswerTargetEnum::Published.to_u32());
+    // time in past - task is in queue now
+    let request = "{\"queue\": \"mail\", \"data\": \"b\", \"at\": 1}".to_string();
+    publish(&request, &mut service_data, &options, &state);
+
+    let (target, _) = list(&String::new(), &mut service_data, &options, &state);
+    assert_eq!(target, AnswerTargetEnum::Forbidden.to_u32());
+    let (target, data) = list(&"mail".to_string(), &mut manager_data, &options, &state);
+    assert_eq!(target, AnswerTargetEnum::ScheduledList.to_u32());
+    let records = Json::from_str(&data).unwrap();
+    assert_eq!(records.as_array().unwrap().len(), 1);
+    assert_eq!(records[0]["id"].as_u64().unwrap().to_string(), id);
+
+    let request = format!("{{\"id\": {}}}", id);
+    let (target, _) = cancel(&request, &mut manager_data, &options, &state);
+    assert_eq!(target, AnswerTargetEnum::Done.to_u32());
+    let (target, _) = cancel(&request, &mut manager_data, &options, &state);
+    assert_eq!(target, AnswerTargetEnum::Rejected.to_u32());
+    let (_, data) = list(&String::new(), &mut manager_data, &options, &state);
+    assert_eq!(data, "[]");
+  }
+
+  #[test]
+  fn test_dead_letters() {
+    let mut options = ProjectOptions::new();
+    let state = BrokerState::new();
+    let mut queue = QueueOptions::new("mail");
+    queue.max_deliveries = Some(1);
+    queue.dead_letter = Some("failed".to_string());
+    options.queues.push(queue.clone());
+    let nack = get_answer_method(CommandTargetEnum::Nack, 2);
+    let list = get_answer_method(CommandTargetEnum::ListDead, 2);
+    let requeue = get_answer_method(CommandTargetEnum::RequeueDead, 2);
+    let purge = get_answer_method(CommandTargetEnum::PurgeDead, 2);
+    let mut server_data = ClientConnectionData::new();
+    server_data.set_cuid("server1".to_string());
+    server_data.set_group(ClientGroupEnum::Server.to_u32());
+    let mut manager_data = ClientConnectionData::new();
+    manager_data.set_group(ClientGroupEnum::Manager.to_u32());
+
+    state.publish(&queue, NewTask::new("poison".to_string(), "service".to_string()));
+    state.consume(&queue, "server1".to_string());
+    state.take_routed();
+    let request = "{\"id\": 1, \"reason\": \"crash\"}".to_string();
+    let (target, _) = nack(&request, &mut server_data, &options, &state);
+    assert_eq!(target, AnswerTargetEnum::Done.to_u32());
+
+    let (target, _) = list(&"failed".to_string(), &mut server_data, &options, &state);
+    assert_eq!(target, AnswerTargetEnum::Forbidden.to_u32());
+    let (target, data) = list(&"failed".to_string(), &mut manager_data, &options, &state);
+    assert_eq!(target, AnswerTargetEnum::DeadList.to_u32());
+    let records = Json::from_str(&data).unwrap();
+    assert_eq!(records[0]["reason"].as_string(), Some("crash"));
+    assert_eq!(records[0]["attempts"].as_u64(), Some(1));
+
+    // task goes to waiting server again
+    state.consume(&queue, "server1".to_string());
+    let request = "{\"queue\": \"failed\"}".to_string();
+    let (_, count) = requeue(&request, &mut manager_data, &options, &state);
+    assert_eq!(count, "1");
+    assert_eq!(state.take_routed().len(), 1);
+    let request = "{\"id\": 1}".to_string();
+    nack(&request, &mut server_data, &options, &state);
+    let request = "{\"queue\": \"failed\", \"id\": 1}".to_string();
+    let (target, count) = purge(&request, &mut manager_data, &options, &state);
+    assert_eq!((target, count.as_str()), (AnswerTargetEnum::Done.to_u32(), "1"));
+    let (_, data) = list(&"failed".to_string(), &mut manager_data, &options, &state);
+    assert_eq!(data, "[]");
+  }
+}
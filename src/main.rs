@@ -4,6 +4,8 @@ extern crate env_logger;
 extern crate rand;
 extern crate crypto;
 extern crate rustc_serialize;
+extern crate rmp;
+extern crate openssl;
 
 mod options;
 mod consts;
@@ -13,6 +15,10 @@ mod common;
 mod handler;
 mod transport;
 mod protocol;
+mod codec;
+mod tls;
+mod state;
+mod queue;
 
 use std::env;
 use consts::common::CONF_ENV_VARIABLE;
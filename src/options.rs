@@ -1,11 +1,16 @@
 pub mod configuration {
+  use codec::FramingEnum;
   use common::helpers::Description;
   use consts::common::{
-    MIN_COMMAND_POOL_SIZE, MIN_BUFFER_SIZE};
+    MIN_COMMAND_POOL_SIZE, MIN_BUFFER_SIZE, MAX_FRAME_SIZE, MAX_COMMAND_SIZE, PIPELINE_SIZE,
+    HEARTBEAT_INTERVAL, HEARTBEAT_MISSES, AUTH_MAX_FAILURES, AUTH_BAN_TIME, AUTH_MAX_BAN_TIME,
+    AUTH_FAILURE_WINDOW, RESUME_TOKEN_TTL, QUEUE_MAX_SIZE, QUEUE_MAX_PRIORITIES,
+    TASK_VISIBILITY_TIMEOUT};
   use std::clone::Clone;
   use std::fs::File;
   use std::io::Read;
   use std::error::Error;
+  use std::env;
   use std::net::{SocketAddr, Ipv4Addr, SocketAddrV4};
   use rustc_serialize::json;
 
@@ -15,24 +20,187 @@ pub mod configuration {
     fn read_from_file(file_path: &str) -> Self;
   }
 
+  // own secret and fixed group of client identity,
+  // identity is common name of client certificate for mutual tls
+  #[derive(RustcDecodable, RustcEncodable, Clone)]
+  pub struct ClientCredentials {
+    pub identity: String,
+    pub secret: String,
+    pub group: u32,
+  }
+
+  // allowed command targets and queues of client group or identity,
+  // no list - all allowed, queue "*" - any queue
+  #[derive(RustcDecodable, RustcEncodable, Clone)]
+  pub struct AclRule {
+    pub group: Option<u32>,
+    pub identity: Option<String>,
+    pub targets: Option<Vec<u32>>,
+    pub queues: Option<Vec<String>>,
+  }
+
+  // one more shared secret for rotation, expires - unix time (sec)
+  #[derive(Clone)]
+  pub struct SharedSecret {
+    pub name: String,
+    pub value: String,
+    pub expires: Option<i64>,
+  }
+
+  // named queue of tasks, max_size - limit of waiting tasks,
+  // visibility_timeout (sec) - time of task in server without Ack,
+  // priorities - levels of priority, aging (sec) - waiting time which raises
+  // priority of task by one level, no aging - low priority tasks wait,
+  // max_deliveries - limit of deliveries, after it task goes to dead letters,
+  // dead_letter - label of dead letters for Manager commands, it is not a queue of consumers
+  // and it differs from names of queues,
+  // ttl (sec) - life of task without own ttl, expired task is not delivered
+  #[derive(RustcDecodable, RustcEncodable, Clone)]
+  pub struct QueueOptions {
+    pub name: String,
+    pub max_size: Option<u32>,
+    pub visibility_timeout: Option<u32>,
+    pub priorities: Option<u32>,
+    pub aging: Option<u32>,
+    pub max_deliveries: Option<u32>,
+    pub dead_letter: Option<String>,
+    pub ttl: Option<u32>,
+  }
+
+  impl QueueOptions {
+    pub fn new(name: &str) -> Self {
+      QueueOptions {
+        name: name.to_string(),
+        max_size: None,
+        visibility_timeout: None,
+        priorities: None,
+        aging: None,
+        max_deliveries: None,
+        dead_letter: None,
+        ttl: None,
+      }
+    }
+  }
+
   pub struct ProjectOptions {
+    // shared secret of clients without identity, empty - disabled
     pub secret: String,
+    pub secrets: Vec<SharedSecret>,
     pub socket: SocketAddr,
     pub workers: u32,
     pub command_buffer: u32,
     pub node: String,
     pub connection_buffer_size: u32,
+    pub max_frame_size: u32,
+    pub max_command_size: u32,
+    pub framing: FramingEnum,
+    pub pipeline_size: u32,
+    pub heartbeat_interval: u32,
+    pub heartbeat_misses: u32,
+    // auth of old clients by sha1 of key and secret
+    pub legacy_auth: bool,
+    // ban of address and identity after auth failures in window (sec)
+    pub auth_max_failures: u32,
+    pub auth_ban_time: u32,
+    pub auth_max_ban_time: u32,
+    pub auth_failure_window: u32,
+    // key of resume tokens, empty - random key of this run
+    pub resume_secret: String,
+    pub resume_ttl: u32,
+    pub credentials: Vec<ClientCredentials>,
+    pub acl: Vec<AclRule>,
+    pub queues: Vec<QueueOptions>,
+    // encrypted listener with certificate and key (pem files)
+    pub tls_socket: Option<SocketAddr>,
+    pub tls_cert: String,
+    pub tls_key: String,
+    // CA (pem file) of client certificates, empty - no client certificates
+    pub tls_ca: String,
+    // no plain listener
+    pub tls_only: bool,
   }
 
   impl ProjectOptions {
-    fn new() -> Self {
+    pub fn new() -> Self {
       ProjectOptions {
         secret: String::new(),
+        secrets: Vec::new(),
         socket: SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), DEFAULT_PORT)),
         workers: 1,
         command_buffer: 1,
         node: String::new(),
         connection_buffer_size: MIN_BUFFER_SIZE as u32,
+        max_frame_size: MAX_FRAME_SIZE,
+        max_command_size: MAX_COMMAND_SIZE,
+        framing: FramingEnum::Length,
+        pipeline_size: PIPELINE_SIZE,
+        heartbeat_interval: HEARTBEAT_INTERVAL,
+        heartbeat_misses: HEARTBEAT_MISSES,
+        legacy_auth: false,
+        auth_max_failures: AUTH_MAX_FAILURES,
+        auth_ban_time: AUTH_BAN_TIME,
+        auth_max_ban_time: AUTH_MAX_BAN_TIME,
+        auth_failure_window: AUTH_FAILURE_WINDOW,
+        resume_secret: String::new(),
+        resume_ttl: RESUME_TOKEN_TTL,
+        credentials: Vec::new(),
+        acl: Vec::new(),
+        queues: Vec::new(),
+        tls_socket: None,
+        tls_cert: String::new(),
+        tls_key: String::new(),
+        tls_ca: String::new(),
+        tls_only: false,
+      }
+    }
+
+    // name and value of shared secrets which are not expired
+    pub fn active_secrets(&self, now: i64) -> Vec<(String, String)> {
+      let mut secrets: Vec<(String, String)> = Vec::new();
+      if !self.secret.is_empty() {
+        secrets.push(("secret".to_string(), self.secret.clone()));
+      }
+      for secret in self.secrets.iter() {
+        match secret.expires {
+          Some(expires) if expires <= now => {},
+          _ => secrets.push((secret.name.clone(), secret.value.clone())),
+        }
+      }
+      secrets
+    }
+
+    pub fn get_credentials(&self, identity: &str) -> Option<&ClientCredentials> {
+      self.credentials.iter().find(|credentials| credentials.identity == identity)
+    }
+
+    // rule of identity is more important than rule of group
+    fn get_acl_rule(&self, identity: &str, group: u32) -> Option<&AclRule> {
+      let identity_rule = self.acl.iter().find(|rule| match rule.identity {
+        Some(ref rule_identity) => !identity.is_empty() && rule_identity == identity,
+        None => false,
+      });
+      match identity_rule {
+        Some(rule) => Some(rule),
+        None => self.acl.iter().find(|rule| rule.group == Some(group)),
+      }
+    }
+
+    pub fn allows_target(&self, identity: &str, group: u32, target: u32) -> bool {
+      match self.get_acl_rule(identity, group) {
+        Some(&AclRule { targets: Some(ref targets), .. }) => targets.contains(&target),
+        _ => true,
+      }
+    }
+
+    pub fn get_queue(&self, name: &str) -> Option<&QueueOptions> {
+      self.queues.iter().find(|queue| queue.name == name)
+    }
+
+    pub fn allows_queue(&self, identity: &str, group: u32, queue: &str) -> bool {
+      match self.get_acl_rule(identity, group) {
+        Some(&AclRule { queues: Some(ref queues), .. }) => queues.iter().any(
+          |name| name == "*" || name == queue),
+        _ => true,
       }
     }
   }
@@ -41,11 +209,33 @@ pub mod configuration {
     fn clone(&self) -> Self {
       ProjectOptions {
         secret: self.secret.clone(),
+        secrets: self.secrets.clone(),
         socket: self.socket.clone(),
         workers: self.workers.clone(),
         command_buffer: self.command_buffer.clone(),
         node: self.node.clone(),
         connection_buffer_size: self.connection_buffer_size.clone(),
+        max_frame_size: self.max_frame_size.clone(),
+        max_command_size: self.max_command_size.clone(),
+        framing: self.framing.clone(),
+        pipeline_size: self.pipeline_size.clone(),
+        heartbeat_interval: self.heartbeat_interval.clone(),
+        heartbeat_misses: self.heartbeat_misses.clone(),
+        legacy_auth: self.legacy_auth.clone(),
+        auth_max_failures: self.auth_max_failures.clone(),
+        auth_ban_time: self.auth_ban_time.clone(),
+        auth_max_ban_time: self.auth_max_ban_time.clone(),
+        auth_failure_window: self.auth_failure_window.clone(),
+        resume_secret: self.resume_secret.clone(),
+        resume_ttl: self.resume_ttl.clone(),
+        credentials: self.credentials.clone(),
+        acl: self.acl.clone(),
+        queues: self.queues.clone(),
+        tls_socket: self.tls_socket.clone(),
+        tls_cert: self.tls_cert.clone(),
+        tls_key: self.tls_key.clone(),
+        tls_ca: self.tls_ca.clone(),
+        tls_only: self.tls_only.clone(),
       }
     }
   }
@@ -56,14 +246,87 @@ pub mod configuration {
     }
   }
 
+  // value of secret is inline, in file or in environment variable
+  #[derive(RustcDecodable, RustcEncodable)]
+  struct JsonSecretRecord {
+    name: Option<String>,
+    value: Option<String>,
+    file: Option<String>,
+    env: Option<String>,
+    expires: Option<i64>,
+  }
+
+  fn load_secret(record: &JsonSecretRecord, index: usize, file_path: &str) -> SharedSecret {
+    let name = match record.name {
+      Some(ref name) => name.clone(),
+      None => format!("secret {}", index + 1),
+    };
+    let value = match (&record.value, &record.file, &record.env) {
+      (&Some(ref value), &None, &None) => value.clone(),
+      (&None, &Some(ref secret_path), &None) => {
+        let mut content = String::new();
+        match File::open(secret_path) {
+          Ok(mut file) => match file.read_to_string(&mut content) {
+            Ok(_) => content.trim_right_matches(|ch| ch == '\n' || ch == '\r').to_string(),
+            Err(err) => {
+              panic!(format!("Secret '{}' file read error: {}", name, err.description()));
+            }
+          },
+          Err(err) => {
+            panic!(format!("Secret '{}' file '{}' error: {:?}", name, secret_path, err));
+          }
+        }
+      },
+      (&None, &None, &Some(ref variable)) => match env::var(variable) {
+        Ok(value) => value,
+        Err(err) => {
+          panic!(format!("Secret '{}' variable '{}' error: {}", name, variable, err));
+        }
+      },
+      _ => {
+        panic!(format!("File '{}' secret '{}' needs one of value, file or env", file_path, name));
+      }
+    };
+    if value.is_empty() {
+      panic!(format!("File '{}' secret '{}' is empty", file_path, name));
+    }
+    SharedSecret {
+      name: name,
+      value: value,
+      expires: record.expires,
+    }
+  }
+
   #[derive(RustcDecodable, RustcEncodable)]
   struct JsonOptionRecord {
-    secret: String,
+    secret: Option<String>,
+    secrets: Option<Vec<JsonSecretRecord>>,
     socket: String,
     workers: u32,
     node: String,
     command_buffer: u32,
     connection_buffer_size: u32,
+    max_frame_size: Option<u32>,
+    max_command_size: Option<u32>,
+    framing: Option<String>,
+    pipeline_size: Option<u32>,
+    heartbeat_interval: Option<u32>,
+    heartbeat_misses: Option<u32>,
+    legacy_auth: Option<bool>,
+    auth_max_failures: Option<u32>,
+    auth_ban_time: Option<u32>,
+    auth_max_ban_time: Option<u32>,
+    auth_failure_window: Option<u32>,
+    resume_secret: Option<String>,
+    resume_ttl: Option<u32>,
+    credentials: Option<Vec<ClientCredentials>>,
+    acl: Option<Vec<AclRule>>,
+    queues: Option<Vec<QueueOptions>>,
+    tls_socket: Option<String>,
+    tls_cert: Option<String>,
+    tls_key: Option<String>,
+    tls_ca: Option<String>,
+    tls_only: Option<bool>,
   }
 
   impl JsonReader for ProjectOptions {
@@ -86,8 +349,94 @@ pub mod configuration {
                 Err(_) => ProjectOptions::new().socket,
               };
               let min_command_pool = MIN_COMMAND_POOL_SIZE as u32;
+              let tls_socket = match json_record.tls_socket {
+                Some(ref tls_socket_str) => match tls_socket_str.parse::<SocketAddr>() {
+                  Ok(addr_value) => Some(addr_value),
+                  Err(_) => {
+                    panic!(format!("File '{}' wrong tls socket: {}", file_path, tls_socket_str));
+                  }
+                },
+                None => None,
+              };
+              let tls_cert = json_record.tls_cert.unwrap_or(String::new());
+              let tls_key = json_record.tls_key.unwrap_or(String::new());
+              if tls_socket.is_some() && (tls_cert.is_empty() || tls_key.is_empty()) {
+                panic!(format!("File '{}' tls socket without certificate or key", file_path));
+              }
+              let tls_only = json_record.tls_only.unwrap_or(false);
+              if tls_only && tls_socket.is_none() {
+                panic!(format!("File '{}' tls only without tls socket", file_path));
+              }
+              let acl = json_record.acl.unwrap_or(Vec::new());
+              for rule in acl.iter() {
+                if rule.group.is_some() == rule.identity.is_some() {
+                  panic!(format!(
+                    "File '{}' acl rule needs group or identity: {:?} {:?}",
+                    file_path, rule.group, rule.identity));
+                }
+              }
+              let credentials = json_record.credentials.unwrap_or(Vec::new());
+              for client in credentials.iter() {
+                if client.identity.is_empty() || client.group < 1 || client.group > 3 {
+                  panic!(format!(
+                    "File '{}' wrong credentials of client: {}", file_path, client.identity));
+                }
+              }
+              let mut queues = json_record.queues.unwrap_or(Vec::new());
+              for (index, queue) in queues.iter().enumerate() {
+                if queue.name.is_empty() || queue.name == "*" ||
+                    queues[..index].iter().any(|other| other.name == queue.name) {
+                  panic!(format!("File '{}' wrong name of queue: '{}'", file_path, queue.name));
+                }
+              }
+              // label of dead letters is not mistaken for a queue
+              let names: Vec<String> = queues.iter().map(|queue| queue.name.clone()).collect();
+              for queue in queues.iter_mut() {
+                queue.max_size = match queue.max_size {
+                  Some(size) if size > 0 => Some(size),
+                  _ => Some(QUEUE_MAX_SIZE),
+                };
+                queue.visibility_timeout = match queue.visibility_timeout {
+                  Some(timeout) if timeout > 0 => Some(timeout),
+                  _ => Some(TASK_VISIBILITY_TIMEOUT),
+                };
+                queue.priorities = match queue.priorities {
+                  Some(levels) if levels > QUEUE_MAX_PRIORITIES => {
+                    panic!(format!(
+                      "File '{}' queue '{}' has more than {} priorities",
+                      file_path, queue.name, QUEUE_MAX_PRIORITIES));
+                  },
+                  Some(levels) if levels > 0 => Some(levels),
+                  _ => Some(1),
+                };
+                queue.aging = match queue.aging {
+                  Some(aging) if aging > 0 => Some(aging),
+                  _ => None,
+                };
+                queue.ttl = match queue.ttl {
+                  Some(ttl) if ttl > 0 => Some(ttl),
+                  _ => None,
+                };
+                queue.max_deliveries = match queue.max_deliveries {
+                  Some(deliveries) if deliveries > 0 => Some(deliveries),
+                  _ => None,
+                };
+                match queue.dead_letter {
+                  Some(ref name) if name.is_empty() || name == "*" ||
+                      names.contains(name) => {
+                    panic!(format!(
+                      "File '{}' queue '{}' has wrong dead letter label", file_path, queue.name));
+                  },
+                  _ => {}
+                }
+              }
               ProjectOptions {
-                secret: json_record.secret,
+                secret: json_record.secret.unwrap_or(String::new()),
+                secrets: match json_record.secrets {
+                  Some(ref records) => records.iter().enumerate().map(
+                    |(index, record)| load_secret(record, index, file_path)).collect(),
+                  None => Vec::new(),
+                },
                 socket: socket,
                 connection_buffer_size: json_record.connection_buffer_size,
                 workers: json_record.workers,
@@ -97,6 +446,65 @@ pub mod configuration {
                     min_command_pool
                   },
                 node: json_record.node,
+                max_frame_size: match json_record.max_frame_size {
+                  Some(size) if size > 0 => size,
+                  _ => MAX_FRAME_SIZE,
+                },
+                max_command_size: match json_record.max_command_size {
+                  Some(size) if size > 0 => size,
+                  _ => MAX_COMMAND_SIZE,
+                },
+                framing: match json_record.framing {
+                  Some(ref name) => match FramingEnum::from_name(name) {
+                    Some(framing) => framing,
+                    None => {
+                      panic!(format!("File '{}' unknown framing: {}", file_path, name));
+                    }
+                  },
+                  None => ProjectOptions::new().framing,
+                },
+                pipeline_size: match json_record.pipeline_size {
+                  Some(size) if size > 0 => size,
+                  _ => PIPELINE_SIZE,
+                },
+                heartbeat_interval: match json_record.heartbeat_interval {
+                  Some(interval) if interval > 0 => interval,
+                  _ => HEARTBEAT_INTERVAL,
+                },
+                heartbeat_misses: match json_record.heartbeat_misses {
+                  Some(misses) if misses > 0 => misses,
+                  _ => HEARTBEAT_MISSES,
+                },
+                legacy_auth: json_record.legacy_auth.unwrap_or(false),
+                auth_max_failures: match json_record.auth_max_failures {
+                  Some(failures) if failures > 0 => failures,
+                  _ => AUTH_MAX_FAILURES,
+                },
+                auth_ban_time: match json_record.auth_ban_time {
+                  Some(ban_time) if ban_time > 0 => ban_time,
+                  _ => AUTH_BAN_TIME,
+                },
+                auth_max_ban_time: match json_record.auth_max_ban_time {
+                  Some(ban_time) if ban_time > 0 => ban_time,
+                  _ => AUTH_MAX_BAN_TIME,
+                },
+                auth_failure_window: match json_record.auth_failure_window {
+                  Some(window) if window > 0 => window,
+                  _ => AUTH_FAILURE_WINDOW,
+                },
+                resume_secret: json_record.resume_secret.unwrap_or(String::new()),
+                resume_ttl: match json_record.resume_ttl {
+                  Some(ttl) if ttl > 0 => ttl,
+                  _ => RESUME_TOKEN_TTL,
+                },
+                credentials: credentials,
+                acl: acl,
+                queues: queues,
+                tls_socket: tls_socket,
+                tls_cert: tls_cert,
+                tls_key: tls_key,
+                tls_ca: json_record.tls_ca.unwrap_or(String::new()),
+                tls_only: tls_only,
               }
             },
             Err(err) => {
@@ -122,6 +530,8 @@ mod tests {
   use std::error::Error;
   use std::net::{SocketAddr, Ipv4Addr, SocketAddrV4};
   use options::configuration::{JsonReader, ProjectOptions};
+  use consts::common::{MAX_FRAME_SIZE, MAX_COMMAND_SIZE, QUEUE_MAX_SIZE, TASK_VISIBILITY_TIMEOUT};
+  use codec::FramingEnum;
 
   #[test]
   fn test_read_json_configuration() {
@@ -143,13 +553,24 @@ mod tests {
     	\"workers\": 8,
     	\"command_buffer\": 1024,
     	\"node\": \"node1\",
-    	\"connection_buffer_size\": 4096}".to_string();
+    	\"connection_buffer_size\": 4096,
+    	\"secrets\": [
+    	  {\"name\": \"old\", \"value\": \"0987654321\", \"expires\": 1000},
+    	  {\"env\": \"ROOMB_TEST_SECRET\"}],
+    	\"credentials\": [{\"identity\": \"manager1\", \"secret\": \"abc\", \"group\": 1}],
+    	\"acl\": [
+    	  {\"group\": 3, \"targets\": [1, 5], \"queues\": [\"tasks\"]},
+    	  {\"identity\": \"service1\", \"queues\": [\"*\"]}],
+    	\"queues\": [{\"name\": \"mail\"}, {\"name\": \"tasks\", \"max_size\": 10, \"visibility_timeout\": 5,
+    	  \"priorities\": 3, \"aging\": 60, \"max_deliveries\": 5, \"dead_letter\": \"failed\",
+    	  \"ttl\": 30}]}".to_string();
     // cargo test  -- --nocapture
     println!("{}", content);
 
     file.write_all(&content.into_bytes());
     file.sync_all();
 
+    env::set_var("ROOMB_TEST_SECRET", "qwerty");
     let options = ProjectOptions::read_from_file(&tmp_path_str);
     let sa = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(100, 100, 100, 100), 8000));
     assert_eq!(options.socket, sa);
@@ -157,6 +578,55 @@ mod tests {
     assert_eq!(options.command_buffer, 1024);
     assert_eq!(options.connection_buffer_size, 4096);
     assert_eq!(options.secret, "1234567890".to_string());
+    assert_eq!(options.active_secrets(999).len(), 3);
+    assert_eq!(
+      options.active_secrets(1000),
+      vec![
+        ("secret".to_string(), "1234567890".to_string()),
+        ("secret 2".to_string(), "qwerty".to_string())]);
     assert_eq!(options.node, "node1".to_string());
+    assert_eq!(options.max_frame_size, MAX_FRAME_SIZE);
+    assert_eq!(options.max_command_size, MAX_COMMAND_SIZE);
+    assert_eq!(options.framing, FramingEnum::Length);
+    assert!(!options.legacy_auth);
+    assert_eq!(options.get_credentials("manager1").unwrap().group, 1);
+    assert!(options.get_credentials("node1").is_none());
+    assert!(options.allows_target("", 3, 5));
+    assert!(!options.allows_target("", 3, 7));
+    assert!(options.allows_target("", 2, 7));
+    assert!(options.allows_target("service1", 3, 7));
+    assert!(!options.allows_queue("service2", 3, "mail"));
+    assert!(options.allows_queue("service1", 3, "mail"));
+    assert_eq!(options.get_queue("mail").unwrap().max_size, Some(QUEUE_MAX_SIZE));
+    assert_eq!(options.get_queue("tasks").unwrap().max_size, Some(10));
+    assert_eq!(
+      options.get_queue("mail").unwrap().visibility_timeout, Some(TASK_VISIBILITY_TIMEOUT));
+    assert_eq!(options.get_queue("tasks").unwrap().visibility_timeout, Some(5));
+    assert_eq!(options.get_queue("mail").unwrap().priorities, Some(1));
+    assert_eq!(options.get_queue("mail").unwrap().aging, None);
+    assert_eq!(options.get_queue("tasks").unwrap().priorities, Some(3));
+    assert_eq!(options.get_queue("tasks").unwrap().aging, Some(60));
+    assert_eq!(options.get_queue("mail").unwrap().max_deliveries, None);
+    assert_eq!(options.get_queue("tasks").unwrap().max_deliveries, Some(5));
+    assert_eq!(options.get_queue("tasks").unwrap().dead_letter, Some("failed".to_string()));
+    assert_eq!(options.get_queue("mail").unwrap().ttl, None);
+    assert_eq!(options.get_queue("tasks").unwrap().ttl, Some(30));
+    assert!(options.get_queue("other").is_none());
+    assert!(options.tls_socket.is_none());
+  }
+
+  #[test]
+  #[should_panic(expected = "wrong dead letter label")]
+  fn test_dead_letter_of_queue_name() {
+    let mut tmp_path = env::temp_dir();
+    let mut rng = rand::thread_rng();
+    tmp_path.push(format!("00-0{}.json", rng.gen::<i32>()));
+    let mut file = File::create(&tmp_path).unwrap();
+    let content = "{\"socket\": \"127.0.0.1:8000\", \"workers\": 1, \"command_buffer\": 16,
+      \"node\": \"node1\", \"connection_buffer_size\": 4096,
+      \"queues\": [{\"name\": \"mail\", \"dead_letter\": \"tasks\"}, {\"name\": \"tasks\"}]}";
+    file.write_all(content.as_bytes()).unwrap();
+    file.sync_all().unwrap();
+    ProjectOptions::read_from_file(tmp_path.to_str().unwrap());
   }
 }
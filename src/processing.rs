@@ -2,41 +2,110 @@ extern crate time;
 
 use common::helpers::Description;
 use connection::init_connection;
-use consts::common::{STD_LOOP_DELAY, NOTARGET_DELAY};
+use consts::common::{STD_LOOP_DELAY, NOTARGET_DELAY, ANSWER_SHIP_TIMEOUT};
 use handler::exec::CommandHandle;
 use options::configuration::ProjectOptions;
+use state::BrokerState;
 use transport::{
   Command, Answer, LockManager, TransportConstructor,
-  TransportCopy, ClientConnectionData};
+  TransportCopy, ClientConnectionData, CuidSource};
 use std::clone::Clone;
 use std::collections::HashSet;
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::io::prelude::*;
 
+// wait free cell in answer pool, answer is dropped after timeout
+fn ship_answer(
+    answer: &Answer,
+    answer_pool: &Mutex<Vec<Answer>>,
+    description: &String,
+    index_num: u32) -> bool {
+  let mut answer_done = false;
+  let iter_limit: u32 = ANSWER_SHIP_TIMEOUT * 1000 / STD_LOOP_DELAY;
+  let mut iter_index = 0;
+  loop {
+    match answer_pool.lock() {
+      Ok(mut answer_pool) => {
+        for answer_cell in answer_pool.iter_mut() {
+          if answer_cell.is_empty() {
+            if answer_cell.lock() {
+              // set answer
+              answer_cell.update(answer);
+              answer_cell.unlock();
+              answer_done = true;
+              break;
+            }
+          }
+        }
+      },
+      Err(err) => {
+        warn!("Answer buffer in worker {} lock error: {}", index_num, err);
+      }
+    }
+    if answer_done {
+      info!("Answer for {} shipped from worker {}", description, index_num);
+      break;
+    } else if iter_index >= iter_limit {
+      error!(
+        "Answer for {} dropped in worker {}, answer pool is full {} sec",
+        description, index_num, ANSWER_SHIP_TIMEOUT);
+      break;
+    } else {
+      warn!("Answer for {} shipping wait in worker {}", description, index_num);
+      iter_index += 1;
+      thread::sleep_ms(STD_LOOP_DELAY);
+    }
+  }
+  answer_done
+}
+
+// cells of closed clients are free, results for them are parked
+fn release_closed_answers(answer_pool: &Mutex<Vec<Answer>>, state: &BrokerState) {
+  let answers: Vec<Answer> = match answer_pool.lock() {
+    Ok(mut answer_pool) => {
+      let mut answers: Vec<Answer> = Vec::new();
+      for answer_cell in answer_pool.iter_mut() {
+        if !answer_cell.is_empty() && !answer_cell.is_locked() &&
+            state.is_offline(&answer_cell.get_cuid()) {
+          answers.push(answer_cell.clone());
+          answer_cell.clear();
+        }
+      }
+      answers
+    },
+    Err(err) => {
+      warn!("Answer buffer of closed clients lock error: {}", err);
+      Vec::new()
+    }
+  };
+  if !answers.is_empty() {
+    info!("Answer pool cells of closed clients released: {}", answers.len());
+    state.park(answers);
+  }
+}
+
 //================
 pub fn start(options: &ProjectOptions) {
   let worker_count = options.workers as u32;
   let mut command_pool: Vec<Command> = Vec::new();
   let mut answer_pool: Vec<Answer> = Vec::new();
-  let mut connection_data_pool: Vec<ClientConnectionData> = Vec::new();
 
   let command_buffer_size = options.command_buffer as usize;
   for _ in 0..command_buffer_size {
     command_pool.push(Command::new());
     answer_pool.push(Answer::new());
-    connection_data_pool.push(ClientConnectionData::new());
   }
 
   let arc_command_pool = Arc::new(Mutex::new(command_pool));
   let arc_answer_pool = Arc::new(Mutex::new(answer_pool));
   let arc_closed_clients_set = Arc::new(Mutex::new(HashSet::new()));
-  let arc_connection_data_pool = Arc::new(Mutex::new(connection_data_pool));
+  let arc_state = Arc::new(BrokerState::new());
 
   for index in 0..worker_count {
     let arc_local_command_pool = arc_command_pool.clone();
     let arc_local_answer_pool = arc_answer_pool.clone();
-    let arc_local_connection_data_pool = arc_connection_data_pool.clone();
+    let arc_local_state = arc_state.clone();
     let local_options = options.clone();
 
     thread::spawn(move || {
@@ -76,34 +145,18 @@ pub fn start(options: &ProjectOptions) {
         if !skip && at_cell_index < command_buffer_size {
           match at_work_command {
             Some(mut work_command) => {
-              let mut connection_data: ClientConnectionData;
-              // get temp connection data
-              loop {
-                match arc_local_connection_data_pool.lock() {
-                  Ok(local_connection_data_pool) => {
-                    connection_data = local_connection_data_pool[at_cell_index].clone();
-                    break;
-                  },
-                  Err(err) => {
-                      warn!("Temp data pull cell {} still busy!", at_cell_index);
-                      thread::sleep_ms(STD_LOOP_DELAY);
-                  }
-                }
-              }
+              // connection data of client at time of command
+              let mut connection_data: ClientConnectionData = work_command.get_connection();
 
               loop {
                 // write command cell free
                 match arc_local_command_pool.lock() {
                   Ok(mut local_command_pool) => {
-                    if local_command_pool[at_cell_index].unlock() {
-                      local_command_pool[at_cell_index].clear();
-                      info!("Command pull cell {} free.", at_cell_index);
-                      break;
-                    } else {
-                      // wtf
-                      warn!("Command pull cell {} still busy!", at_cell_index);
-                      thread::sleep_ms(STD_LOOP_DELAY);
-                    }
+                    // unlocked full cell would be taken by other worker again
+                    local_command_pool[at_cell_index].unlock();
+                    local_command_pool[at_cell_index].clear();
+                    info!("Command pull cell {} free.", at_cell_index);
+                    break;
                   },
                   Err(err) => {
                     warn!("Commad buffer for free cell in worker {} lock error: {}", index_num, err);
@@ -113,50 +166,21 @@ pub fn start(options: &ProjectOptions) {
               }
               // end loop
               // save answer
-              let answer = work_command.execute(&local_options, &mut connection_data);
-              let mut answer_done = false;
+              let mut answer = work_command.execute(
+                &local_options, &mut connection_data, &arc_local_state);
+              // connection takes changes with answer
+              answer.set_connection(&connection_data);
               let command_description = work_command.description();
-              // set temp             
-              loop {
-                match arc_local_connection_data_pool.lock() {
-                  Ok(mut local_connection_data_pool) => {
-                    local_connection_data_pool[at_cell_index].copy(&connection_data);
-                    connection_data.clear();
-                    break;
-                  },
-                  Err(err) => {
-                      warn!("Temp data pull cell {} still busy!", at_cell_index);
-                      thread::sleep_ms(STD_LOOP_DELAY);
-                  }
-                }
+              if arc_local_state.is_offline(&answer.get_cuid()) {
+                // connection of command is closed already
+                info!("Answer for {} of closed client dropped", command_description);
+              } else {
+                ship_answer(&answer, &arc_local_answer_pool, &command_description, index_num);
               }
-              // free answer cell
-              loop {
-                match arc_local_answer_pool.lock() {
-                  Ok(mut answer_pool) => {
-                    for answer_cell in answer_pool.iter_mut() {
-                      if !answer_cell.is_shipped() {
-                        if answer_cell.lock() {
-                          // set answer
-                          answer_cell.update(&answer);
-                          answer_cell.unlock();
-                          answer_done = true;
-                          break;
-                        }
-                      }
-                    }
-                  },
-                  Err(err) => {
-                    warn!("Answer buffer in worker {} lock error: {}", index_num, err);
-                  }
-                }
-                if answer_done {
-                  info!("Answer for {} shipped from worker {}", command_description, index_num);
-                  break;
-                } else {
-                  warn!("Answer for {} shipping wait in worker {}", command_description, index_num);
-                  thread::sleep_ms(STD_LOOP_DELAY);                  
-                }
+              // tasks of queues for other clients
+              for routed_answer in arc_local_state.take_routed() {
+                ship_answer(
+                  &routed_answer, &arc_local_answer_pool, &routed_answer.description(), index_num);
               }
             },
             None => {
@@ -177,15 +201,39 @@ pub fn start(options: &ProjectOptions) {
 
     });
   }
+  // clear closed client, tasks without Ack return to queues, delayed tasks go to queues,
+  // parked answers of gone clients are dropped
+  let arc_local_closed_clients_set = arc_closed_clients_set.clone();
+  let arc_local_answer_pool = arc_answer_pool.clone();
+  let arc_local_state = arc_state.clone();
+  let local_options = options.clone();
+  thread::spawn(move || {
+    loop {
+      thread::sleep_ms(NOTARGET_DELAY);
+      let closed_clients: Vec<String> = match arc_local_closed_clients_set.lock() {
+        Ok(mut closed_clients_set) => closed_clients_set.drain().collect(),
+        Err(err) => {
+          warn!("Closed clients lock error: {}", err);
+          Vec::new()
+        }
+      };
+      for cuid in closed_clients {
+        arc_local_state.client_closed(&cuid, &local_options);
+      }
+      release_closed_answers(&arc_local_answer_pool, &arc_local_state);
+      arc_local_state.requeue_expired(&local_options);
+      arc_local_state.release_scheduled(&local_options);
+      arc_local_state.drop_parked(&local_options);
+      arc_local_state.drop_auth_failures(&local_options);
+      for routed_answer in arc_local_state.take_routed() {
+        ship_answer(&routed_answer, &arc_local_answer_pool, &routed_answer.description(), 0);
+      }
+    }
+  });
   // connection
   init_connection(
     &options,
     arc_command_pool,
     arc_answer_pool,
-    arc_connection_data_pool,
     arc_closed_clients_set);
-  // clear closed client
-  loop {
-    thread::sleep_ms(NOTARGET_DELAY);
-  }
 }
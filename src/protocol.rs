@@ -1,4 +1,5 @@
 use common::helpers::Description;
+use consts::common::LEGACY_PROTOCOL_VERSION;
 use rustc_serialize::json;
 
 // === data ===
@@ -8,6 +9,22 @@ pub enum CommandTargetEnum {
   Unknown,
   Auth,
   ClientData,
+  Ping,
+  ListBans,
+  ClearBans,
+  Publish,
+  Consume,
+  Ack,
+  Nack,
+  ListScheduled,
+  CancelScheduled,
+  ListDead,
+  RequeueDead,
+  PurgeDead,
+  Result,
+  Subscribe,
+  Unsubscribe,
+  PublishTopic,
 }
 
 pub enum AnswerTargetEnum {
@@ -19,6 +36,42 @@ pub enum AnswerTargetEnum {
   WhoAreYou,
   Wait,
   TakeCuid,
+  Pong,
+  Forbidden,
+  BanList,
+  Done,
+  Published,
+  Task,
+  Rejected,
+  ScheduledList,
+  DeadList,
+  Expired,
+  Result,
+  Event,
+}
+
+// codes of errors are stable part of protocol
+pub enum ErrorCodeEnum {
+  Unknown,
+  MalformedFrame,
+  UnknownTarget,
+  AuthFailed,
+  NotAuthorized,
+  Overloaded,
+  QueueNotFound,
+  UnsupportedCodec,
+  UnsupportedVersion,
+  HeartbeatTimeout,
+  BadClientData,
+  InvalidPart,
+  TooLarge,
+  Forbidden,
+  Banned,
+  InvalidToken,
+  BadRequest,
+  TaskNotFound,
+  NotSubscribed,
+  NoCapableServer,
 }
 
 pub enum ClientGroupEnum {
@@ -31,6 +84,129 @@ pub enum ClientGroupEnum {
 pub struct ClientDescription {
   group: u32,
   cid: String,
+  // resume token from TakeCuid answer for cid
+  token: Option<String>,
+  // task methods which server can handle
+  methods: Option<Vec<String>>,
+}
+
+// SigIn data of client with options of connection
+#[derive(RustcDecodable, RustcEncodable)]
+pub struct SigInRequest {
+  codec: Option<String>,
+  versions: Option<Vec<u32>>,
+  // heartbeat interval (seconds) which client wants
+  heartbeat: Option<u32>,
+  // client identity from credentials of server
+  identity: Option<String>,
+}
+
+// Auth data of client: hmac-sha256 (hex) by secret of server key + client nonce
+#[derive(RustcDecodable, RustcEncodable)]
+pub struct AuthRequest {
+  nonce: String,
+  hmac: String,
+}
+
+// answer for SigIn with versions of protocol
+#[derive(RustcDecodable, RustcEncodable)]
+pub struct SigInAnswer {
+  key: String,
+  version: u32,
+  codec: String,
+  heartbeat: u32,
+}
+
+// data of error answer since protocol version 2
+#[derive(RustcDecodable, RustcEncodable)]
+pub struct ErrorAnswer {
+  code: u32,
+  error: String,
+  message: String,
+}
+
+// Publish data of service: task data to queue,
+// priority - level from zero (default, lowest),
+// delay (sec) or at (unix time, sec) - time of delivery,
+// ttl (sec) - life of task in queue, notify - Expired answer for service,
+// method - task goes only to servers with this method
+#[derive(RustcDecodable, RustcEncodable)]
+pub struct PublishRequest {
+  queue: String,
+  data: String,
+  priority: Option<u32>,
+  delay: Option<u32>,
+  at: Option<i64>,
+  method: Option<String>,
+  ttl: Option<u32>,
+  notify: Option<bool>,
+}
+
+// Consume data of server, each Consume waits one task
+#[derive(RustcDecodable, RustcEncodable)]
+pub struct ConsumeRequest {
+  queue: String,
+}
+
+// data of answer about task for service
+#[derive(RustcDecodable, RustcEncodable)]
+pub struct TaskNotice {
+  id: u64,
+  queue: String,
+}
+
+// Ack or Nack data of server, reason of failure for Nack
+#[derive(RustcDecodable, RustcEncodable)]
+pub struct TaskRequest {
+  id: u64,
+  reason: Option<String>,
+}
+
+// result of task from server for service which published it
+#[derive(RustcDecodable, RustcEncodable)]
+pub struct ResultRequest {
+  id: u64,
+  data: String,
+}
+
+// data of Result answer for service
+#[derive(RustcDecodable, RustcEncodable)]
+pub struct TaskResult {
+  id: u64,
+  queue: String,
+  data: String,
+}
+
+// topic of Subscribe or Unsubscribe, data of event for PublishTopic
+#[derive(RustcDecodable, RustcEncodable)]
+pub struct TopicRequest {
+  topic: String,
+  data: Option<String>,
+}
+
+// data of Event answer for subscriber
+#[derive(RustcDecodable, RustcEncodable)]
+pub struct TopicEvent {
+  topic: String,
+  data: String,
+}
+
+// dead letter queue and task of it for manager, no id - all tasks
+#[derive(RustcDecodable, RustcEncodable)]
+pub struct DeadRequest {
+  queue: String,
+  id: Option<u64>,
+}
+
+// data of Task answer for server, attempt - number of delivery
+#[derive(RustcDecodable, RustcEncodable)]
+pub struct TaskAnswer {
+  id: u64,
+  queue: String,
+  data: String,
+  attempt: u32,
+  priority: u32,
+  method: Option<String>,
 }
 
 // === trait ===
@@ -53,6 +229,24 @@ pub trait LookAsClientGroupEnum {
 // === impl ====
 
 impl ClientDescription {
+  pub fn get_group(&self) -> u32 {
+    self.group
+  }
+
+  pub fn get_token(&self) -> String {
+    match self.token {
+      Some(ref token) => token.clone(),
+      None => String::new(),
+    }
+  }
+
+  pub fn get_methods(&self) -> Vec<String> {
+    match self.methods {
+      Some(ref methods) => methods.iter().filter(|method| !method.is_empty()).cloned().collect(),
+      None => Vec::new(),
+    }
+  }
+
   pub fn get_cid(&self) -> Option<String> {
     if self.cid.len() > 0 {
       Some(self.cid.clone())
@@ -61,6 +255,230 @@ impl ClientDescription {
     }
   }
 }
+impl SigInRequest {
+  pub fn get_codec(&self) -> Option<String> {
+    match self.codec {
+      Some(ref codec) if codec.len() > 0 => Some(codec.clone()),
+      _ => None,
+    }
+  }
+
+  pub fn get_versions(&self) -> Option<Vec<u32>> {
+    self.versions.clone()
+  }
+
+  pub fn get_heartbeat(&self) -> Option<u32> {
+    self.heartbeat
+  }
+
+  pub fn get_identity(&self) -> Option<String> {
+    match self.identity {
+      Some(ref identity) if identity.len() > 0 => Some(identity.clone()),
+      _ => None,
+    }
+  }
+}
+
+impl AuthRequest {
+  pub fn get_nonce(&self) -> String {
+    self.nonce.clone()
+  }
+
+  pub fn get_hmac(&self) -> String {
+    self.hmac.clone()
+  }
+}
+
+impl PublishRequest {
+  pub fn get_queue(&self) -> String {
+    self.queue.clone()
+  }
+
+  pub fn get_data(&self) -> String {
+    self.data.clone()
+  }
+
+  pub fn get_priority(&self) -> u32 {
+    self.priority.unwrap_or(0)
+  }
+
+  pub fn get_ttl(&self) -> Option<u32> {
+    self.ttl
+  }
+
+  pub fn get_notify(&self) -> bool {
+    self.notify.unwrap_or(false)
+  }
+
+  pub fn get_method(&self) -> Option<String> {
+    match self.method {
+      Some(ref method) if !method.is_empty() => Some(method.clone()),
+      _ => None,
+    }
+  }
+
+  // time of delivery, none - now, error if both delay and time
+  pub fn get_due(&self, now: i64) -> Result<Option<i64>, ()> {
+    match (self.delay, self.at) {
+      (Some(delay), None) => Ok(Some(now + delay as i64)),
+      (None, Some(at)) => Ok(Some(at)),
+      (None, None) => Ok(None),
+      _ => Err(()),
+    }
+  }
+}
+
+impl ConsumeRequest {
+  pub fn get_queue(&self) -> String {
+    self.queue.clone()
+  }
+}
+
+impl TaskNotice {
+  pub fn new(id: u64, queue: String) -> Self {
+    TaskNotice {
+      id: id,
+      queue: queue,
+    }
+  }
+}
+
+impl TaskRequest {
+  pub fn get_id(&self) -> u64 {
+    self.id
+  }
+
+  pub fn get_reason(&self) -> String {
+    match self.reason {
+      Some(ref reason) => reason.clone(),
+      None => String::new(),
+    }
+  }
+}
+
+impl ResultRequest {
+  pub fn get_id(&self) -> u64 {
+    self.id
+  }
+
+  pub fn get_data(&self) -> String {
+    self.data.clone()
+  }
+}
+
+impl TaskResult {
+  pub fn new(id: u64, queue: String, data: String) -> Self {
+    TaskResult {
+      id: id,
+      queue: queue,
+      data: data,
+    }
+  }
+}
+
+impl TopicRequest {
+  pub fn get_topic(&self) -> String {
+    self.topic.clone()
+  }
+
+  pub fn get_data(&self) -> String {
+    match self.data {
+      Some(ref data) => data.clone(),
+      None => String::new(),
+    }
+  }
+}
+
+impl TopicEvent {
+  pub fn new(topic: String, data: String) -> Self {
+    TopicEvent {
+      topic: topic,
+      data: data,
+    }
+  }
+}
+
+impl DeadRequest {
+  pub fn get_queue(&self) -> String {
+    self.queue.clone()
+  }
+
+  pub fn get_id(&self) -> Option<u64> {
+    self.id
+  }
+}
+
+impl TaskAnswer {
+  pub fn new(
+      id: u64,
+      queue: String,
+      data: String,
+      attempt: u32,
+      priority: u32,
+      method: Option<String>) -> Self {
+    TaskAnswer {
+      id: id,
+      queue: queue,
+      data: data,
+      attempt: attempt,
+      priority: priority,
+      method: method,
+    }
+  }
+}
+
+impl ErrorCodeEnum {
+  pub fn name(&self) -> String {
+    match *self {
+      ErrorCodeEnum::Unknown => "unknown",
+      ErrorCodeEnum::MalformedFrame => "malformed_frame",
+      ErrorCodeEnum::UnknownTarget => "unknown_target",
+      ErrorCodeEnum::AuthFailed => "auth_failed",
+      ErrorCodeEnum::NotAuthorized => "not_authorized",
+      ErrorCodeEnum::Overloaded => "overloaded",
+      ErrorCodeEnum::QueueNotFound => "queue_not_found",
+      ErrorCodeEnum::UnsupportedCodec => "unsupported_codec",
+      ErrorCodeEnum::UnsupportedVersion => "unsupported_version",
+      ErrorCodeEnum::HeartbeatTimeout => "heartbeat_timeout",
+      ErrorCodeEnum::BadClientData => "bad_client_data",
+      ErrorCodeEnum::InvalidPart => "invalid_part",
+      ErrorCodeEnum::TooLarge => "too_large",
+      ErrorCodeEnum::Forbidden => "forbidden",
+      ErrorCodeEnum::Banned => "banned",
+      ErrorCodeEnum::InvalidToken => "invalid_token",
+      ErrorCodeEnum::BadRequest => "bad_request",
+      ErrorCodeEnum::TaskNotFound => "task_not_found",
+      ErrorCodeEnum::NotSubscribed => "not_subscribed",
+      ErrorCodeEnum::NoCapableServer => "no_capable_server",
+    }.to_string()
+  }
+
+  // data of error answer for client with this protocol version,
+  // old clients take text message only
+  pub fn answer_data(&self, message: &str, version: u32) -> String {
+    if version > LEGACY_PROTOCOL_VERSION {
+      let answer = ErrorAnswer {
+        code: self.to_u32(),
+        error: self.name(),
+        message: message.to_string(),
+      };
+      json::encode(&answer).unwrap()
+    } else {
+      message.to_string()
+    }
+  }
+}
+
+impl SigInAnswer {
+  pub fn new(key: String, version: u32, codec: String, heartbeat: u32) -> Self {
+    SigInAnswer {
+      key: key,
+      version: version,
+      codec: codec,
+      heartbeat: heartbeat,
+    }
+  }
+}
 // === impl trait ===
 impl TargetAsDigit for CommandTargetEnum {
   fn to_u32(&self) -> u32 {
@@ -70,6 +488,22 @@ impl TargetAsDigit for CommandTargetEnum {
       CommandTargetEnum::SigIn => 2,
       CommandTargetEnum::Auth => 3,
       CommandTargetEnum::ClientData => 4,
+      CommandTargetEnum::Ping => 5,
+      CommandTargetEnum::ListBans => 6,
+      CommandTargetEnum::ClearBans => 7,
+      CommandTargetEnum::Publish => 8,
+      CommandTargetEnum::Consume => 9,
+      CommandTargetEnum::Ack => 10,
+      CommandTargetEnum::Nack => 11,
+      CommandTargetEnum::ListScheduled => 12,
+      CommandTargetEnum::CancelScheduled => 13,
+      CommandTargetEnum::ListDead => 14,
+      CommandTargetEnum::RequeueDead => 15,
+      CommandTargetEnum::PurgeDead => 16,
+      CommandTargetEnum::Result => 17,
+      CommandTargetEnum::Subscribe => 18,
+      CommandTargetEnum::Unsubscribe => 19,
+      CommandTargetEnum::PublishTopic => 20,
     }
   }
 }
@@ -85,6 +519,45 @@ impl TargetAsDigit for AnswerTargetEnum {
       AnswerTargetEnum::WhoAreYou => 5,
       AnswerTargetEnum::Wait => 6,
       AnswerTargetEnum::TakeCuid => 7,
+      AnswerTargetEnum::Pong => 8,
+      AnswerTargetEnum::Forbidden => 9,
+      AnswerTargetEnum::BanList => 10,
+      AnswerTargetEnum::Done => 11,
+      AnswerTargetEnum::Published => 12,
+      AnswerTargetEnum::Task => 13,
+      AnswerTargetEnum::Rejected => 14,
+      AnswerTargetEnum::ScheduledList => 15,
+      AnswerTargetEnum::DeadList => 16,
+      AnswerTargetEnum::Expired => 17,
+      AnswerTargetEnum::Result => 18,
+      AnswerTargetEnum::Event => 19,
+    }
+  }
+}
+
+impl TargetAsDigit for ErrorCodeEnum {
+  fn to_u32(&self) -> u32 {
+    match(*self) {
+      ErrorCodeEnum::Unknown => 0,
+      ErrorCodeEnum::MalformedFrame => 1,
+      ErrorCodeEnum::UnknownTarget => 2,
+      ErrorCodeEnum::AuthFailed => 3,
+      ErrorCodeEnum::NotAuthorized => 4,
+      ErrorCodeEnum::Overloaded => 5,
+      ErrorCodeEnum::QueueNotFound => 6,
+      ErrorCodeEnum::UnsupportedCodec => 7,
+      ErrorCodeEnum::UnsupportedVersion => 8,
+      ErrorCodeEnum::HeartbeatTimeout => 9,
+      ErrorCodeEnum::BadClientData => 10,
+      ErrorCodeEnum::InvalidPart => 11,
+      ErrorCodeEnum::TooLarge => 12,
+      ErrorCodeEnum::Forbidden => 13,
+      ErrorCodeEnum::Banned => 14,
+      ErrorCodeEnum::InvalidToken => 15,
+      ErrorCodeEnum::BadRequest => 16,
+      ErrorCodeEnum::TaskNotFound => 17,
+      ErrorCodeEnum::NotSubscribed => 18,
+      ErrorCodeEnum::NoCapableServer => 19,
     }
   }
 }
@@ -107,6 +580,22 @@ impl Description for CommandTargetEnum {
       CommandTargetEnum::SigIn => "'sigin'",
       CommandTargetEnum::Auth => "'auth'",
       CommandTargetEnum::ClientData => "'client data'",
+      CommandTargetEnum::Ping => "'ping'",
+      CommandTargetEnum::ListBans => "'list bans'",
+      CommandTargetEnum::ClearBans => "'clear bans'",
+      CommandTargetEnum::Publish => "'publish'",
+      CommandTargetEnum::Consume => "'consume'",
+      CommandTargetEnum::Ack => "'ack'",
+      CommandTargetEnum::Nack => "'nack'",
+      CommandTargetEnum::ListScheduled => "'list scheduled'",
+      CommandTargetEnum::CancelScheduled => "'cancel scheduled'",
+      CommandTargetEnum::ListDead => "'list dead'",
+      CommandTargetEnum::RequeueDead => "'requeue dead'",
+      CommandTargetEnum::PurgeDead => "'purge dead'",
+      CommandTargetEnum::Result => "'result'",
+      CommandTargetEnum::Subscribe => "'subscribe'",
+      CommandTargetEnum::Unsubscribe => "'unsubscribe'",
+      CommandTargetEnum::PublishTopic => "'publish topic'",
     }.to_string()
   }
 }
@@ -122,6 +611,18 @@ impl Description for AnswerTargetEnum {
       AnswerTargetEnum::WhoAreYou => "'auth successful get client info'",
       AnswerTargetEnum::Wait => "'wait'",
       AnswerTargetEnum::TakeCuid => "'take cuid'",
+      AnswerTargetEnum::Pong => "'pong'",
+      AnswerTargetEnum::Forbidden => "'forbidden'",
+      AnswerTargetEnum::BanList => "'ban list'",
+      AnswerTargetEnum::Done => "'done'",
+      AnswerTargetEnum::Published => "'published'",
+      AnswerTargetEnum::Task => "'task'",
+      AnswerTargetEnum::Rejected => "'rejected'",
+      AnswerTargetEnum::ScheduledList => "'scheduled list'",
+      AnswerTargetEnum::DeadList => "'dead list'",
+      AnswerTargetEnum::Expired => "'expired'",
+      AnswerTargetEnum::Result => "'result'",
+      AnswerTargetEnum::Event => "'event'",
     }.to_string()
   }
 }
@@ -143,6 +644,22 @@ impl LookAsTargetCommandEnum for u32 {
       2 => CommandTargetEnum::SigIn,
       3 => CommandTargetEnum::Auth,
       4 => CommandTargetEnum::ClientData,
+      5 => CommandTargetEnum::Ping,
+      6 => CommandTargetEnum::ListBans,
+      7 => CommandTargetEnum::ClearBans,
+      8 => CommandTargetEnum::Publish,
+      9 => CommandTargetEnum::Consume,
+      10 => CommandTargetEnum::Ack,
+      11 => CommandTargetEnum::Nack,
+      12 => CommandTargetEnum::ListScheduled,
+      13 => CommandTargetEnum::CancelScheduled,
+      14 => CommandTargetEnum::ListDead,
+      15 => CommandTargetEnum::RequeueDead,
+      16 => CommandTargetEnum::PurgeDead,
+      17 => CommandTargetEnum::Result,
+      18 => CommandTargetEnum::Subscribe,
+      19 => CommandTargetEnum::Unsubscribe,
+      20 => CommandTargetEnum::PublishTopic,
       _ => CommandTargetEnum::Unknown,
     }
   }
@@ -156,6 +673,20 @@ impl LookAsTargetAnswerEnum for u32 {
       3 => AnswerTargetEnum::VerificationRequest,
       4 => AnswerTargetEnum::Error,
       5 => AnswerTargetEnum::WhoAreYou,
+      6 => AnswerTargetEnum::Wait,
+      7 => AnswerTargetEnum::TakeCuid,
+      8 => AnswerTargetEnum::Pong,
+      9 => AnswerTargetEnum::Forbidden,
+      10 => AnswerTargetEnum::BanList,
+      11 => AnswerTargetEnum::Done,
+      12 => AnswerTargetEnum::Published,
+      13 => AnswerTargetEnum::Task,
+      14 => AnswerTargetEnum::Rejected,
+      15 => AnswerTargetEnum::ScheduledList,
+      16 => AnswerTargetEnum::DeadList,
+      17 => AnswerTargetEnum::Expired,
+      18 => AnswerTargetEnum::Result,
+      19 => AnswerTargetEnum::Event,
       _ => AnswerTargetEnum::Unknown,
     }
   }
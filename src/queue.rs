@@ -0,0 +1,890 @@
+use consts::common::{QUEUE_MAX_SIZE, TASK_VISIBILITY_TIMEOUT};
+use options::configuration::{ProjectOptions, QueueOptions};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+// === data ===
+// task of service in queue
+#[derive(Clone)]
+pub struct Task {
+  id: u64,
+  queue: String,
+  data: String,
+  // deliveries to servers
+  attempts: u32,
+  // higher priority is delivered first
+  priority: u32,
+  // time (sec) of publish
+  created: i64,
+  // reason of last failed delivery
+  error: String,
+  // time (sec) of expiry, own ttl of task (none - ttl of queue) for requeue
+  expires: Option<i64>,
+  ttl: Option<u32>,
+  // cuid of service and its wish of expiry notice
+  producer: String,
+  notify: bool,
+  // task goes only to server with this method
+  method: Option<String>,
+  // binary payload of MessagePack client
+  blob: Vec<u8>,
+}
+
+// data of Publish command
+pub struct NewTask {
+  pub data: String,
+  // cuid of service
+  pub producer: String,
+  pub priority: u32,
+  // seconds of life in queue, none - ttl of queue
+  pub ttl: Option<u32>,
+  // producer takes notice of expiry
+  pub notify: bool,
+  pub method: Option<String>,
+  pub blob: Vec<u8>,
+}
+
+// task of server without Ack or Nack
+struct InFlightTask {
+  task: Task,
+  consumer: String,
+  // time (sec) of return to queue
+  deadline: i64,
+}
+
+// task after max deliveries
+struct DeadTask {
+  task: Task,
+  // time (sec) of move to dead letters
+  died: i64,
+}
+
+#[derive(RustcDecodable, RustcEncodable)]
+pub struct DeadRecord {
+  id: u64,
+  queue: String,
+  data: String,
+  attempts: u32,
+  reason: String,
+  died: i64,
+}
+
+// server which waits task, methods of server from client data
+struct Consumer {
+  cuid: String,
+  methods: Vec<String>,
+}
+
+// waiting tasks by priority and servers which wait tasks
+struct TaskQueue {
+  levels: Vec<VecDeque<Task>>,
+  // server for each Consume command
+  consumers: VecDeque<Consumer>,
+}
+
+// task which waits time of delivery
+#[derive(RustcDecodable, RustcEncodable)]
+pub struct ScheduledRecord {
+  id: u64,
+  queue: String,
+  priority: u32,
+  // unix time (sec)
+  due: i64,
+}
+
+// queues of options, queue is created by first use
+pub struct QueueRegistry {
+  queues: HashMap<String, TaskQueue>,
+  in_flight: HashMap<u64, InFlightTask>,
+  // delayed tasks by time of delivery
+  scheduled: BTreeMap<(i64, u64), Task>,
+  // failed tasks by name of dead letter queue
+  dead: HashMap<String, VecDeque<DeadTask>>,
+  // expired tasks for notice of producer
+  notices: Vec<Task>,
+  last_id: u64,
+}
+
+// === impl ===
+impl Task {
+  pub fn get_id(&self) -> u64 {
+    self.id
+  }
+
+  pub fn get_queue(&self) -> String {
+    self.queue.clone()
+  }
+
+  pub fn get_data(&self) -> String {
+    self.data.clone()
+  }
+
+  pub fn get_attempts(&self) -> u32 {
+    self.attempts
+  }
+
+  pub fn get_priority(&self) -> u32 {
+    self.priority
+  }
+
+  pub fn get_producer(&self) -> String {
+    self.producer.clone()
+  }
+
+  pub fn get_method(&self) -> Option<String> {
+    self.method.clone()
+  }
+
+  pub fn get_blob(&self) -> Vec<u8> {
+    self.blob.clone()
+  }
+
+  fn is_expired(&self, now: i64) -> bool {
+    match self.expires {
+      Some(expires) => expires <= now,
+      None => false,
+    }
+  }
+
+  // priority is raised by one level for each aging interval of waiting
+  fn effective_priority(&self, aging: u32, now: i64) -> i64 {
+    if aging > 0 && now > self.created {
+      self.priority as i64 + (now - self.created) / aging as i64
+    } else {
+      self.priority as i64
+    }
+  }
+}
+
+impl NewTask {
+  pub fn new(data: String, producer: String) -> Self {
+    NewTask {
+      data: data,
+      producer: producer,
+      priority: 0,
+      ttl: None,
+      notify: false,
+      method: None,
+      blob: Vec::new(),
+    }
+  }
+}
+
+impl Consumer {
+  // task without method goes to any server
+  fn can_handle(&self, task: &Task) -> bool {
+    match task.method {
+      Some(ref method) => self.methods.contains(method),
+      None => true,
+    }
+  }
+}
+
+impl TaskQueue {
+  fn new() -> Self {
+    TaskQueue {
+      levels: Vec::new(),
+      consumers: VecDeque::new(),
+    }
+  }
+
+  fn len(&self) -> usize {
+    self.levels.iter().map(|level| level.len()).sum()
+  }
+
+  fn is_empty(&self) -> bool {
+    self.levels.iter().all(|level| level.is_empty())
+  }
+
+  // returned task is at head of own level
+  fn push(&mut self, task: Task, front: bool) {
+    let level = task.priority as usize;
+    while self.levels.len() <= level {
+      self.levels.push(VecDeque::new());
+    }
+    if front {
+      self.levels[level].push_front(task);
+    } else {
+      self.levels[level].push_back(task);
+    }
+  }
+
+  // head of level with highest priority, FIFO in level,
+  // older task of aged level goes before task of same priority
+  fn pop(&mut self, aging: u32, now: i64) -> Option<Task> {
+    let mut best: Option<(usize, i64, i64)> = None;
+    for (level, tasks) in self.levels.iter().enumerate() {
+      match tasks.front() {
+        Some(task) => {
+          let priority = task.effective_priority(aging, now);
+          best = match best {
+            Some((_, best_priority, best_created)) if best_priority > priority ||
+              (best_priority == priority && best_created <= task.created) => best,
+            _ => Some((level, priority, task.created)),
+          };
+        },
+        None => {}
+      }
+    }
+    match best {
+      Some((level, _, _)) => self.levels[level].pop_front(),
+      None => None,
+    }
+  }
+}
+
+// time of expiry by own ttl of task or ttl of queue, zero ttl - no expiry
+fn expiry(ttl: Option<u32>, queue: &QueueOptions, start: i64) -> Option<i64> {
+  let ttl = match ttl {
+    Some(ttl) => ttl,
+    None => queue.ttl.unwrap_or(0),
+  };
+  if ttl > 0 { Some(start + ttl as i64) } else { None }
+}
+
+impl QueueRegistry {
+  pub fn new() -> Self {
+    QueueRegistry {
+      queues: HashMap::new(),
+      in_flight: HashMap::new(),
+      scheduled: BTreeMap::new(),
+      dead: HashMap::new(),
+      notices: Vec::new(),
+      last_id: 0,
+    }
+  }
+
+  fn get_queue(&mut self, name: &str) -> &mut TaskQueue {
+    self.queues.entry(name.to_string()).or_insert(TaskQueue::new())
+  }
+
+  // new task if queue is not full, priority is limited by levels of queue,
+  // life of task starts at time of delivery
+  fn create_task(&mut self, queue: &QueueOptions, new_task: NewTask, start: i64) -> Option<Task> {
+    if !self.has_place(queue, start) {
+      return None;
+    }
+    let levels = queue.priorities.unwrap_or(1);
+    self.last_id += 1;
+    Some(Task {
+      id: self.last_id,
+      queue: queue.name.clone(),
+      data: new_task.data,
+      attempts: 0,
+      priority: if new_task.priority < levels { new_task.priority } else { levels - 1 },
+      created: start,
+      error: String::new(),
+      expires: expiry(new_task.ttl, queue, start),
+      ttl: new_task.ttl,
+      producer: new_task.producer,
+      notify: new_task.notify,
+      method: new_task.method,
+      blob: new_task.blob,
+    })
+  }
+
+  // queue is not full after removal of expired tasks
+  fn has_place(&mut self, queue: &QueueOptions, now: i64) -> bool {
+    let max_size = queue.max_size.unwrap_or(0) as usize;
+    if max_size > 0 && self.size(&queue.name) + self.scheduled_size(&queue.name) >= max_size {
+      // place of expired tasks
+      self.remove_expired(queue, now);
+      self.size(&queue.name) + self.scheduled_size(&queue.name) < max_size
+    } else {
+      true
+    }
+  }
+
+  // id of new task, none if queue is full
+  pub fn publish(&mut self, queue: &QueueOptions, new_task: NewTask, now: i64) -> Option<u64> {
+    match self.create_task(queue, new_task, now) {
+      Some(task) => {
+        let id = task.id;
+        self.get_queue(&queue.name).push(task, false);
+        Some(id)
+      },
+      None => None,
+    }
+  }
+
+  // task goes to queue at due time (sec)
+  pub fn schedule(&mut self, queue: &QueueOptions, new_task: NewTask, due: i64) -> Option<u64> {
+    match self.create_task(queue, new_task, due) {
+      Some(task) => {
+        let id = task.id;
+        self.scheduled.insert((due, id), task);
+        Some(id)
+      },
+      None => None,
+    }
+  }
+
+  // names of queues with due tasks
+  pub fn release_scheduled(&mut self, now: i64) -> Vec<String> {
+    let mut names: Vec<String> = Vec::new();
+    let due_keys: Vec<(i64, u64)> = self.scheduled.range(..(now + 1, 0)).map(|(key, _)| *key).collect();
+    for key in due_keys {
+      match self.scheduled.remove(&key) {
+        Some(task) => {
+          let name = task.queue.clone();
+          self.get_queue(&name).push(task, false);
+          if !names.contains(&name) {
+            names.push(name);
+          }
+        },
+        None => {}
+      }
+    }
+    names
+  }
+
+  // delayed tasks of queue, all with empty name
+  pub fn get_scheduled(&self, name: &str) -> Vec<ScheduledRecord> {
+    self.scheduled.iter().filter(|&(_, task)| name.is_empty() || task.queue == name).map(
+      |(&(due, id), task)| ScheduledRecord {
+        id: id,
+        queue: task.queue.clone(),
+        priority: task.priority,
+        due: due,
+      }
+    ).collect()
+  }
+
+  pub fn cancel_scheduled(&mut self, id: u64) -> bool {
+    let key = self.scheduled.keys().find(|&&(_, task_id)| task_id == id).cloned();
+    match key {
+      Some(key) => self.scheduled.remove(&key).is_some(),
+      None => false,
+    }
+  }
+
+  fn scheduled_size(&self, name: &str) -> usize {
+    self.scheduled.values().filter(|task| task.queue == name).count()
+  }
+
+  pub fn consume(&mut self, queue: &QueueOptions, consumer: String, methods: Vec<String>) {
+    self.get_queue(&queue.name).consumers.push_back(Consumer {
+      cuid: consumer,
+      methods: methods,
+    });
+  }
+
+  // tasks for servers which wait it, in order of Consume commands,
+  // task is in flight until Ack or Nack or visibility timeout
+  pub fn dispatch(&mut self, queue: &QueueOptions, now: i64) -> Vec<(String, Task)> {
+    let mut deliveries: Vec<(String, Task)> = Vec::new();
+    let deadline = now + queue.visibility_timeout.unwrap_or(TASK_VISIBILITY_TIMEOUT) as i64;
+    let aging = queue.aging.unwrap_or(0);
+    let mut expired: Vec<Task> = Vec::new();
+    let mut held: Vec<Task> = Vec::new();
+    {
+      let task_queue = self.get_queue(&queue.name);
+      while !task_queue.consumers.is_empty() {
+        let mut task = match task_queue.pop(aging, now) {
+          Some(task) => task,
+          None => break,
+        };
+        // expired task is not delivered
+        if task.is_expired(now) {
+          expired.push(task);
+          continue;
+        }
+        // first waiting server with method of task
+        let index = task_queue.consumers.iter().position(|consumer| consumer.can_handle(&task));
+        match index.and_then(|index| task_queue.consumers.remove(index)) {
+          Some(consumer) => {
+            task.attempts += 1;
+            deliveries.push((consumer.cuid, task));
+          },
+          None => held.push(task),
+        }
+      }
+      // tasks without capable server keep own places
+      for task in held.into_iter().rev() {
+        task_queue.push(task, true);
+      }
+    }
+    for task in expired {
+      self.expire(task, queue, now);
+    }
+    for &(ref consumer, ref task) in deliveries.iter() {
+      self.in_flight.insert(task.id, InFlightTask {
+        task: task.clone(),
+        consumer: consumer.clone(),
+        deadline: deadline,
+      });
+    }
+    deliveries
+  }
+
+  // failed task returns to head of queue or goes to dead letters after max deliveries,
+  // name of queue of returned task
+  fn requeue(&mut self, mut task: Task, reason: &str, options: &ProjectOptions, now: i64) -> Option<String> {
+    task.error = reason.to_string();
+    let (max_deliveries, dead_letter) = match options.get_queue(&task.queue) {
+      Some(queue) => (queue.max_deliveries.unwrap_or(0), queue.dead_letter.clone()),
+      None => (0, None),
+    };
+    if max_deliveries > 0 && task.attempts >= max_deliveries {
+      self.dead_letter(task, dead_letter, now);
+      None
+    } else {
+      let name = task.queue.clone();
+      self.get_queue(&name).push(task, true);
+      Some(name)
+    }
+  }
+
+  fn remove_expired(&mut self, queue: &QueueOptions, now: i64) {
+    let mut expired: Vec<Task> = Vec::new();
+    {
+      let task_queue = self.get_queue(&queue.name);
+      for level in task_queue.levels.iter_mut() {
+        let (old, live): (Vec<Task>, Vec<Task>) = level.drain(..).partition(
+          |task| task.is_expired(now));
+        level.extend(live);
+        expired.extend(old);
+      }
+    }
+    for task in expired {
+      self.expire(task, queue, now);
+    }
+  }
+
+  // expired task goes to dead letters or is dropped
+  fn expire(&mut self, mut task: Task, queue: &QueueOptions, now: i64) {
+    task.error = "expired".to_string();
+    if task.notify {
+      self.notices.push(task.clone());
+    }
+    match queue.dead_letter {
+      Some(ref name) => self.dead_letter(task, Some(name.clone()), now),
+      None => {
+        debug!("Task {} of queue '{}' is expired", task.id, task.queue);
+      }
+    }
+  }
+
+  // expired tasks which producers wait notice of
+  pub fn take_notices(&mut self) -> Vec<Task> {
+    self.notices.drain(..).collect()
+  }
+
+  // task without dead letter queue is dropped
+  fn dead_letter(&mut self, task: Task, dead_letter: Option<String>, now: i64) {
+    match dead_letter {
+      Some(name) => {
+        warn!(
+          "Task {} of queue '{}' goes to dead letters '{}' after {} deliveries: {}",
+          task.id, task.queue, name, task.attempts, task.error);
+        let dead = self.dead.entry(name.clone()).or_insert(VecDeque::new());
+        if dead.len() >= QUEUE_MAX_SIZE as usize {
+          match dead.pop_front() {
+            Some(oldest) => warn!(
+              "Dead letters '{}' are full, oldest task {} of queue '{}' is dropped",
+              name, oldest.task.id, oldest.task.queue),
+            None => {}
+          }
+        }
+        dead.push_back(DeadTask {
+          task: task,
+          died: now,
+        });
+      },
+      None => {
+        warn!(
+          "Task {} of queue '{}' is dropped after {} deliveries: {}",
+          task.id, task.queue, task.attempts, task.error);
+      }
+    }
+  }
+
+  pub fn get_dead(&self, name: &str) -> Vec<DeadRecord> {
+    match self.dead.get(name) {
+      Some(dead) => dead.iter().map(|dead_task| DeadRecord {
+        id: dead_task.task.id,
+        queue: dead_task.task.queue.clone(),
+        data: dead_task.task.data.clone(),
+        attempts: dead_task.task.attempts,
+        reason: dead_task.task.error.clone(),
+        died: dead_task.died,
+      }).collect(),
+      None => Vec::new(),
+    }
+  }
+
+  // dead tasks of one id or all, removed tasks
+  fn take_dead(&mut self, name: &str, id: Option<u64>) -> Vec<DeadTask> {
+    match self.dead.get_mut(name) {
+      Some(dead) => {
+        let (taken, kept): (Vec<DeadTask>, Vec<DeadTask>) = dead.drain(..).partition(
+          |dead_task| id.is_none() || id == Some(dead_task.task.id));
+        dead.extend(kept);
+        taken
+      },
+      None => Vec::new(),
+    }
+  }
+
+  // dead tasks go to own queues as new with new ttl, tasks of full or unknown queues stay,
+  // number of requeued tasks and names of queues
+  pub fn requeue_dead(
+      &mut self,
+      name: &str,
+      id: Option<u64>,
+      options: &ProjectOptions,
+      now: i64) -> (usize, Vec<String>) {
+    let mut count = 0;
+    let mut names: Vec<String> = Vec::new();
+    let mut kept: Vec<DeadTask> = Vec::new();
+    for dead_task in self.take_dead(name, id) {
+      let queue = match options.get_queue(&dead_task.task.queue) {
+        Some(queue) if self.has_place(queue, now) => queue,
+        _ => {
+          warn!(
+            "Dead task {} stays in '{}', queue '{}' is full or unknown",
+            dead_task.task.id, name, dead_task.task.queue);
+          kept.push(dead_task);
+          continue;
+        }
+      };
+      let mut task = dead_task.task;
+      task.attempts = 0;
+      task.created = now;
+      task.expires = expiry(task.ttl, queue, now);
+      task.error.clear();
+      let queue_name = task.queue.clone();
+      self.get_queue(&queue_name).push(task, false);
+      count += 1;
+      if !names.contains(&queue_name) {
+        names.push(queue_name);
+      }
+    }
+    if !kept.is_empty() {
+      let dead = self.dead.entry(name.to_string()).or_insert(VecDeque::new());
+      for dead_task in kept.into_iter().rev() {
+        dead.push_front(dead_task);
+      }
+    }
+    (count, names)
+  }
+
+  pub fn purge_dead(&mut self, name: &str, id: Option<u64>) -> usize {
+    self.take_dead(name, id).len()
+  }
+
+  fn take_in_flight(&mut self, id: u64, consumer: &str) -> Option<Task> {
+    let own = match self.in_flight.get(&id) {
+      Some(in_flight) => in_flight.consumer == consumer,
+      None => false,
+    };
+    if own {
+      self.in_flight.remove(&id).map(|in_flight| in_flight.task)
+    } else {
+      None
+    }
+  }
+
+  // task is done by server, none if it is not in flight
+  pub fn ack(&mut self, id: u64, consumer: &str) -> Option<Task> {
+    self.take_in_flight(id, consumer)
+  }
+
+  // task of server is failed, none if it is not in flight,
+  // queue name of returned task if it is not in dead letters
+  pub fn nack(
+      &mut self,
+      id: u64,
+      consumer: &str,
+      reason: &str,
+      options: &ProjectOptions,
+      now: i64) -> Option<Option<String>> {
+    match self.take_in_flight(id, consumer) {
+      Some(task) => Some(self.requeue(task, reason, options, now)),
+      None => None,
+    }
+  }
+
+  // names of queues with returned tasks after visibility timeout
+  pub fn requeue_expired(&mut self, options: &ProjectOptions, now: i64) -> Vec<String> {
+    let expired: Vec<u64> = self.in_flight.iter().filter(
+      |&(_, in_flight)| in_flight.deadline <= now).map(|(id, _)| *id).collect();
+    self.requeue_in_flight(expired, "visibility timeout", options, now)
+  }
+
+  fn requeue_in_flight(
+      &mut self, mut ids: Vec<u64>, reason: &str, options: &ProjectOptions, now: i64) -> Vec<String> {
+    let mut names: Vec<String> = Vec::new();
+    // older tasks are at head of queue
+    ids.sort_by(|a, b| b.cmp(a));
+    for id in ids {
+      let task = match self.in_flight.remove(&id) {
+        Some(in_flight) => in_flight.task,
+        None => continue,
+      };
+      match self.requeue(task, reason, options, now) {
+        Some(name) => if !names.contains(&name) {
+          names.push(name);
+        },
+        None => {}
+      }
+    }
+    names
+  }
+
+  // Consume commands of closed connection are removed, its tasks return to queues,
+  // names of queues with returned tasks
+  pub fn remove_consumer(&mut self, cuid: &str, options: &ProjectOptions, now: i64) -> Vec<String> {
+    for queue in self.queues.values_mut() {
+      queue.consumers.retain(|consumer| consumer.cuid != cuid);
+    }
+    let ids: Vec<u64> = self.in_flight.iter().filter(
+      |&(_, in_flight)| in_flight.consumer == cuid).map(|(id, _)| *id).collect();
+    self.requeue_in_flight(ids, "connection closed", options, now)
+  }
+
+  pub fn in_flight_size(&self) -> usize {
+    self.in_flight.len()
+  }
+
+  pub fn size(&self, name: &str) -> usize {
+    match self.queues.get(name) {
+      Some(queue) => queue.len(),
+      None => 0,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use options::configuration::{ProjectOptions, QueueOptions};
+  use queue::{QueueRegistry, NewTask};
+
+  fn task(data: &str, priority: u32) -> NewTask {
+    let mut new_task = NewTask::new(data.to_string(), "s1".to_string());
+    new_task.priority = priority;
+    new_task
+  }
+
+  fn queue_options(name: &str, max_size: u32) -> QueueOptions {
+    let mut queue = QueueOptions::new(name);
+    queue.max_size = Some(max_size);
+    queue.visibility_timeout = Some(30);
+    queue.priorities = Some(3);
+    queue
+  }
+
+  #[test]
+  fn test_publish_consume() {
+    let queue = queue_options("mail", 2);
+    let mut registry = QueueRegistry::new();
+    assert_eq!(registry.publish(&queue, task("a", 0), 0), Some(1));
+    assert_eq!(registry.publish(&queue, task("b", 0), 0), Some(2));
+    assert_eq!(registry.publish(&queue, task("c", 0), 0), None);
+    assert!(registry.dispatch(&queue, 0).is_empty());
+
+    registry.consume(&queue, "w1".to_string(), Vec::new());
+    registry.consume(&queue, "w2".to_string(), Vec::new());
+    registry.consume(&queue, "w1".to_string(), Vec::new());
+    let deliveries = registry.dispatch(&queue, 0);
+    assert_eq!(deliveries.len(), 2);
+    assert_eq!(deliveries[0].0, "w1");
+    assert_eq!(deliveries[0].1.get_data(), "a");
+    assert_eq!(deliveries[1].0, "w2");
+    assert_eq!(deliveries[1].1.get_id(), 2);
+    assert_eq!(registry.size("mail"), 0);
+    assert_eq!(registry.in_flight_size(), 2);
+
+    assert!(registry.ack(1, "w2").is_none());
+    assert_eq!(registry.ack(1, "w1").unwrap().get_producer(), "s1");
+    assert!(registry.ack(1, "w1").is_none());
+    // task of closed connection returns to queue
+    let options = ProjectOptions::new();
+    assert_eq!(registry.remove_consumer("w1", &options, 0), Vec::<String>::new());
+    assert_eq!(registry.remove_consumer("w2", &options, 0), vec!["mail".to_string()]);
+    assert_eq!(registry.size("mail"), 1);
+    assert!(registry.dispatch(&queue, 0).is_empty());
+  }
+
+  #[test]
+  fn test_redelivery() {
+    let queue = queue_options("mail", 10);
+    let mut registry = QueueRegistry::new();
+    registry.publish(&queue, task("a", 0), 0);
+    registry.publish(&queue, task("b", 0), 0);
+    registry.consume(&queue, "w1".to_string(), Vec::new());
+    registry.consume(&queue, "w1".to_string(), Vec::new());
+    assert_eq!(registry.dispatch(&queue, 100).len(), 2);
+
+    let options = ProjectOptions::new();
+    assert_eq!(registry.nack(2, "w1", "error", &options, 100), Some(Some("mail".to_string())));
+    assert!(registry.requeue_expired(&options, 129).is_empty());
+    assert_eq!(registry.requeue_expired(&options, 130), vec!["mail".to_string()]);
+    assert_eq!(registry.in_flight_size(), 0);
+    // order of queue is kept
+    registry.consume(&queue, "w2".to_string(), Vec::new());
+    let deliveries = registry.dispatch(&queue, 200);
+    assert_eq!(deliveries[0].1.get_id(), 1);
+    assert_eq!(deliveries[0].1.get_attempts(), 2);
+  }
+
+  #[test]
+  fn test_priorities() {
+    let mut queue = queue_options("mail", 10);
+    let mut registry = QueueRegistry::new();
+    for &(data, priority) in [("a", 0), ("b", 2), ("c", 1), ("d", 2), ("e", 5)].iter() {
+      registry.publish(&queue, task(data, priority), 100);
+    }
+    for _ in 0..5 {
+      registry.consume(&queue, "w1".to_string(), Vec::new());
+    }
+    let order: Vec<String> = registry.dispatch(&queue, 100).iter().map(
+      |&(_, ref task)| task.get_data()).collect();
+    assert_eq!(order, vec!["b", "d", "e", "c", "a"]);
+
+    // old task of low priority is raised by aging
+    queue.aging = Some(10);
+    registry.publish(&queue, task("old", 0), 100);
+    registry.publish(&queue, task("new", 2), 120);
+    registry.publish(&queue, task("newest", 2), 121);
+    for _ in 0..3 {
+      registry.consume(&queue, "w1".to_string(), Vec::new());
+    }
+    let order: Vec<String> = registry.dispatch(&queue, 121).iter().map(
+      |&(_, ref task)| task.get_data()).collect();
+    assert_eq!(order, vec!["old", "new", "newest"]);
+  }
+
+  #[test]
+  fn test_scheduled() {
+    let queue = queue_options("mail", 3);
+    let mut registry = QueueRegistry::new();
+    assert_eq!(registry.schedule(&queue, task("late", 0), 200), Some(1));
+    assert_eq!(registry.schedule(&queue, task("soon", 0), 150), Some(2));
+    assert_eq!(registry.schedule(&queue, task("other", 0), 300), Some(3));
+    // scheduled tasks are in size of queue
+    assert_eq!(registry.publish(&queue, task("now", 0), 100), None);
+    assert!(registry.cancel_scheduled(3));
+    assert!(!registry.cancel_scheduled(3));
+    let scheduled = registry.get_scheduled("mail");
+    assert_eq!(scheduled.len(), 2);
+    assert_eq!(scheduled[0].id, 2);
+    assert!(registry.get_scheduled("other").is_empty());
+
+    assert!(registry.release_scheduled(149).is_empty());
+    assert_eq!(registry.release_scheduled(150), vec!["mail".to_string()]);
+    registry.consume(&queue, "w1".to_string(), Vec::new());
+    registry.consume(&queue, "w1".to_string(), Vec::new());
+    let deliveries = registry.dispatch(&queue, 150);
+    assert_eq!(deliveries.len(), 1);
+    assert_eq!(deliveries[0].1.get_data(), "soon");
+    registry.release_scheduled(250);
+    assert_eq!(registry.dispatch(&queue, 250)[0].1.get_data(), "late");
+  }
+
+  #[test]
+  fn test_dead_letters() {
+    let mut queue = queue_options("mail", 10);
+    queue.max_deliveries = Some(2);
+    queue.dead_letter = Some("failed".to_string());
+    let mut options = ProjectOptions::new();
+    options.queues.push(queue.clone());
+    let mut registry = QueueRegistry::new();
+    registry.publish(&queue, task("poison", 0), 100);
+    registry.publish(&queue, task("good", 0), 100);
+    for now in 100..102 {
+      registry.consume(&queue, "w1".to_string(), Vec::new());
+      let deliveries = registry.dispatch(&queue, now);
+      assert_eq!(deliveries[0].1.get_data(), "poison");
+      registry.nack(1, "w1", "crash", &options, now);
+    }
+    assert_eq!(registry.size("mail"), 1);
+    let dead = registry.get_dead("failed");
+    assert_eq!(dead.len(), 1);
+    assert_eq!((dead[0].id, dead[0].attempts, dead[0].reason.as_str()), (1, 2, "crash"));
+
+    assert_eq!(registry.requeue_dead("failed", Some(5), &options, 110).0, 0);
+    // full queue doesn't take dead task
+    options.queues[0].max_size = Some(1);
+    assert_eq!(registry.requeue_dead("failed", None, &options, 110).0, 0);
+    assert_eq!(registry.get_dead("failed").len(), 1);
+    options.queues[0].max_size = Some(10);
+    let requeued = registry.requeue_dead("failed", None, &options, 110);
+    assert_eq!(requeued, (1, vec!["mail".to_string()]));
+    assert_eq!(registry.size("mail"), 2);
+    assert!(registry.get_dead("failed").is_empty());
+    // without dead letter queue task is dropped
+    queue.dead_letter = None;
+    options.queues[0] = queue.clone();
+    registry.consume(&queue, "w1".to_string(), Vec::new());
+    registry.dispatch(&queue, 120);
+    registry.requeue_expired(&options, 200);
+    registry.consume(&queue, "w1".to_string(), Vec::new());
+    registry.dispatch(&queue, 200);
+    assert_eq!(registry.remove_consumer("w1", &options, 210), Vec::<String>::new());
+    assert_eq!(registry.size("mail"), 1);
+    assert_eq!(registry.purge_dead("failed", None), 0);
+  }
+
+  #[test]
+  fn test_ttl() {
+    let mut queue = queue_options("mail", 3);
+    queue.ttl = Some(10);
+    let mut registry = QueueRegistry::new();
+    let mut short = task("short", 0);
+    short.ttl = Some(5);
+    short.notify = true;
+    registry.publish(&queue, short, 100);
+    registry.publish(&queue, task("default", 0), 100);
+    let mut endless = task("endless", 0);
+    endless.ttl = Some(0);
+    registry.publish(&queue, endless, 100);
+    // place of expired task
+    assert_eq!(registry.publish(&queue, task("full", 0), 104), None);
+    assert_eq!(registry.publish(&queue, task("new", 0), 105), Some(4));
+    assert_eq!(registry.take_notices()[0].get_data(), "short");
+    assert!(registry.take_notices().is_empty());
+
+    queue.dead_letter = Some("failed".to_string());
+    for _ in 0..3 {
+      registry.consume(&queue, "w1".to_string(), Vec::new());
+    }
+    let deliveries = registry.dispatch(&queue, 110);
+    let data: Vec<String> = deliveries.iter().map(|&(_, ref task)| task.get_data()).collect();
+    assert_eq!(data, vec!["endless", "new"]);
+    assert_eq!(registry.get_dead("failed")[0].reason, "expired");
+    assert!(registry.take_notices().is_empty());
+    // requeued task lives ttl of queue again
+    let mut options = ProjectOptions::new();
+    options.queues.push(queue.clone());
+    assert_eq!(registry.requeue_dead("failed", None, &options, 200).0, 1);
+    registry.consume(&queue, "w1".to_string(), Vec::new());
+    assert_eq!(registry.dispatch(&queue, 205)[0].1.get_data(), "default");
+  }
+
+  #[test]
+  fn test_methods() {
+    let queue = queue_options("mail", 10);
+    let mut registry = QueueRegistry::new();
+    let tasks = [("resize", Some("image")), ("plain", None), ("send", Some("mail"))];
+    for &(data, method) in tasks.iter() {
+      let mut new_task = task(data, 0);
+      new_task.method = method.map(|method| method.to_string());
+      registry.publish(&queue, new_task, 0);
+    }
+    registry.consume(&queue, "w1".to_string(), vec!["mail".to_string()]);
+    let deliveries = registry.dispatch(&queue, 0);
+    assert_eq!(deliveries[0].0, "w1");
+    assert_eq!(deliveries[0].1.get_data(), "plain");
+    registry.consume(&queue, "w1".to_string(), vec!["mail".to_string()]);
+    registry.consume(&queue, "w2".to_string(), Vec::new());
+    let deliveries = registry.dispatch(&queue, 0);
+    assert_eq!(deliveries.len(), 1);
+    assert_eq!(deliveries[0].1.get_data(), "send");
+    // task waits server with method in own place
+    assert_eq!(registry.size("mail"), 1);
+    registry.consume(&queue, "w3".to_string(), vec!["image".to_string()]);
+    let deliveries = registry.dispatch(&queue, 0);
+    assert_eq!(deliveries[0].0, "w3");
+    assert_eq!(deliveries[0].1.get_data(), "resize");
+  }
+}
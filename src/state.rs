@@ -0,0 +1,796 @@
+extern crate time;
+
+use common::helpers::get_random_string;
+use consts::common::RESUME_KEY_SIZE;
+use options::configuration::{ProjectOptions, QueueOptions};
+use protocol::{AnswerTargetEnum, TargetAsDigit, TaskAnswer, TaskNotice, TaskResult, TopicEvent};
+use queue::{QueueRegistry, ScheduledRecord, DeadRecord, NewTask, Task};
+use rustc_serialize::json;
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use transport::{Answer, CuidSource, TransportConstructor};
+
+// === data ===
+// failed auth of client address or identity
+pub struct AuthFailure {
+  failures: u32,
+  // time (sec) of first failure in window
+  first: i64,
+  // time (sec) of ban end
+  until: i64,
+}
+
+#[derive(RustcDecodable, RustcEncodable)]
+pub struct BanRecord {
+  source: String,
+  name: String,
+  failures: u32,
+  // seconds of ban
+  left: i64,
+}
+
+// answers for producer after close of its connection,
+// offline - closed connections of cuid without resume, it is negative if resume is before close
+pub struct ParkedClient {
+  offline: i32,
+  // time (sec) of last close
+  closed: i64,
+  answers: Vec<Answer>,
+}
+
+// methods from client data of server
+pub struct ServerMethods {
+  methods: Vec<String>,
+}
+
+// data of broker which is shared by workers
+pub struct BrokerState {
+  auth_failures: Mutex<HashMap<(String, String), AuthFailure>>,
+  // key of resume tokens without key in options
+  resume_key: String,
+  queues: Mutex<QueueRegistry>,
+  // answers for other clients, worker moves it to answer pool
+  routed: Mutex<Vec<Answer>>,
+  // answers for closed clients, it goes to routed after resume
+  parked: Mutex<HashMap<String, ParkedClient>>,
+  // cuids of subscribers by topic
+  topics: Mutex<HashMap<String, HashSet<String>>>,
+  // connected servers by cuid
+  servers: Mutex<HashMap<String, ServerMethods>>,
+}
+
+// === impl ===
+impl ParkedClient {
+  fn new() -> Self {
+    ParkedClient {
+      offline: 0,
+      closed: 0,
+      answers: Vec::new(),
+    }
+  }
+}
+
+impl BrokerState {
+  pub fn new() -> Self {
+    BrokerState {
+      auth_failures: Mutex::new(HashMap::new()),
+      resume_key: get_random_string(RESUME_KEY_SIZE),
+      queues: Mutex::new(QueueRegistry::new()),
+      routed: Mutex::new(Vec::new()),
+      parked: Mutex::new(HashMap::new()),
+      topics: Mutex::new(HashMap::new()),
+      servers: Mutex::new(HashMap::new()),
+    }
+  }
+
+  pub fn get_resume_key(&self) -> &str {
+    &self.resume_key
+  }
+
+  fn auth_keys(address: &str, identity: &str) -> Vec<(String, String)> {
+    let mut keys = vec![("address".to_string(), address.to_string())];
+    if !identity.is_empty() {
+      keys.push(("identity".to_string(), identity.to_string()));
+    }
+    keys
+  }
+
+  // seconds to end of ban of address or identity
+  pub fn auth_ban(&self, address: &str, identity: &str) -> i64 {
+    let now = time::get_time().sec;
+    match self.auth_failures.lock() {
+      Ok(failures) => BrokerState::auth_keys(address, identity).iter().map(
+        |key| match failures.get(key) {
+          Some(failure) if failure.until > now => failure.until - now,
+          _ => 0,
+        }
+      ).max().unwrap_or(0),
+      Err(err) => {
+        error!("Auth failures lock error: {}", err);
+        0
+      }
+    }
+  }
+
+  // ban after limit of failures in window, each next failure doubles ban time,
+  // count starts again after window without ban
+  pub fn auth_failed(&self, address: &str, identity: &str, options: &ProjectOptions, now: i64) {
+    let max_failures = options.auth_max_failures;
+    let max_ban_time = options.auth_max_ban_time as u64;
+    match self.auth_failures.lock() {
+      Ok(mut failures) => {
+        for key in BrokerState::auth_keys(address, identity) {
+          let failure = failures.entry(key.clone()).or_insert(
+            AuthFailure { failures: 0, first: now, until: 0 });
+          if failure.until <= now && now - failure.first > options.auth_failure_window as i64 {
+            failure.failures = 0;
+            failure.first = now;
+          }
+          failure.failures += 1;
+          if failure.failures >= max_failures {
+            let power = failure.failures - max_failures;
+            let ban = if power < 32 {
+              (options.auth_ban_time as u64) << power
+            } else {
+              max_ban_time
+            };
+            let ban = if ban > max_ban_time { max_ban_time } else { ban };
+            failure.until = now + ban as i64;
+            warn!(
+              "Auth lockout of {} '{}' for {} sec after {} failures",
+              key.0, key.1, ban, failure.failures);
+          }
+        }
+      },
+      Err(err) => {
+        error!("Auth failures lock error: {}", err);
+      }
+    }
+  }
+
+  pub fn auth_passed(&self, address: &str, identity: &str) {
+    match self.auth_failures.lock() {
+      Ok(mut failures) => {
+        for key in BrokerState::auth_keys(address, identity) {
+          failures.remove(&key);
+        }
+      },
+      Err(err) => {
+        error!("Auth failures lock error: {}", err);
+      }
+    }
+  }
+
+  // failures without ban after window are forgotten
+  pub fn drop_auth_failures(&self, options: &ProjectOptions) {
+    let now = time::get_time().sec;
+    match self.auth_failures.lock() {
+      Ok(mut failures) => {
+        let size = failures.len();
+        failures.retain(|_, failure| failure.until > now ||
+          now - failure.first <= options.auth_failure_window as i64);
+        if failures.len() < size {
+          debug!("Old auth failures dropped: {}", size - failures.len());
+        }
+      },
+      Err(err) => {
+        error!("Auth failures lock error: {}", err);
+      }
+    }
+  }
+
+  pub fn get_bans(&self) -> Vec<BanRecord> {
+    let now = time::get_time().sec;
+    match self.auth_failures.lock() {
+      Ok(failures) => failures.iter().filter(|&(_, failure)| failure.until > now).map(
+        |(key, failure)| BanRecord {
+          source: key.0.clone(),
+          name: key.1.clone(),
+          failures: failure.failures,
+          left: failure.until - now,
+        }
+      ).collect(),
+      Err(err) => {
+        error!("Auth failures lock error: {}", err);
+        Vec::new()
+      }
+    }
+  }
+
+  // clear bans of address or identity, all bans with empty name
+  pub fn clear_bans(&self, name: &str) -> usize {
+    match self.auth_failures.lock() {
+      Ok(mut failures) => {
+        let size = failures.len();
+        if name.is_empty() {
+          failures.clear();
+        } else {
+          failures.retain(|key, _| key.1 != name);
+        }
+        info!("Auth bans cleared: '{}'", name);
+        size - failures.len()
+      },
+      Err(err) => {
+        error!("Auth failures lock error: {}", err);
+        0
+      }
+    }
+  }
+
+  // Task answers with cuid of server
+  fn route_tasks(&self, deliveries: Vec<(String, Task)>) {
+    if deliveries.is_empty() {
+      return;
+    }
+    match self.routed.lock() {
+      Ok(mut routed) => {
+        for (consumer, task) in deliveries {
+          let data = TaskAnswer::new(
+            task.get_id(), task.get_queue(), task.get_data(), task.get_attempts(),
+            task.get_priority(), task.get_method());
+          let mut answer = Answer::new();
+          answer.set_target(AnswerTargetEnum::Task.to_u32());
+          answer.set_data(json::encode(&data).unwrap());
+          answer.set_blob(task.get_blob());
+          answer.complete(consumer.clone());
+          debug!("Task {} of queue '{}' goes to {}", task.get_id(), task.get_queue(), consumer);
+          routed.push(answer);
+        }
+      },
+      Err(err) => {
+        error!("Routed answers lock error: {}", err);
+      }
+    }
+  }
+
+  // answers for services go to routed or wait resume of closed client
+  fn route_producer(&self, answers: Vec<Answer>) {
+    if answers.is_empty() {
+      return;
+    }
+    match (self.parked.lock(), self.routed.lock()) {
+      (Ok(mut parked), Ok(mut routed)) => {
+        for answer in answers {
+          match parked.get_mut(&answer.get_cuid()) {
+            Some(ref mut client) if client.offline > 0 => {
+              debug!("Answer for closed client {} is parked", answer.get_cuid());
+              client.answers.push(answer);
+            },
+            _ => routed.push(answer),
+          }
+        }
+      },
+      (Err(err), _) => {
+        error!("Parked answers lock error: {}", err);
+      },
+      (_, Err(err)) => {
+        error!("Routed answers lock error: {}", err);
+      }
+    }
+  }
+
+  // Expired answers with cuid of service
+  fn route_notices(&self, notices: Vec<Task>) {
+    let answers = notices.iter().map(|task| {
+      let data = TaskNotice::new(task.get_id(), task.get_queue());
+      let mut answer = Answer::new();
+      answer.set_target(AnswerTargetEnum::Expired.to_u32());
+      answer.set_data(json::encode(&data).unwrap());
+      answer.complete(task.get_producer());
+      answer
+    }).collect();
+    self.route_producer(answers);
+  }
+
+  fn dispatch_queue(&self, queues: &mut QueueRegistry, queue: &QueueOptions, now: i64) {
+    self.route_tasks(queues.dispatch(queue, now));
+    self.route_notices(queues.take_notices());
+  }
+
+  // id of new task, none if queue is full
+  pub fn publish(&self, queue: &QueueOptions, new_task: NewTask) -> Option<u64> {
+    match self.queues.lock() {
+      Ok(mut queues) => {
+        let now = time::get_time().sec;
+        let id = queues.publish(queue, new_task, now);
+        self.dispatch_queue(&mut queues, queue, now);
+        id
+      },
+      Err(err) => {
+        error!("Queues lock error: {}", err);
+        None
+      }
+    }
+  }
+
+  // task goes to queue at due time (sec)
+  pub fn schedule(&self, queue: &QueueOptions, new_task: NewTask, due: i64) -> Option<u64> {
+    match self.queues.lock() {
+      Ok(mut queues) => {
+        let id = queues.schedule(queue, new_task, due);
+        self.route_notices(queues.take_notices());
+        id
+      },
+      Err(err) => {
+        error!("Queues lock error: {}", err);
+        None
+      }
+    }
+  }
+
+  // delayed tasks go to queues at due time
+  pub fn release_scheduled(&self, options: &ProjectOptions) {
+    match self.queues.lock() {
+      Ok(mut queues) => {
+        let names = queues.release_scheduled(time::get_time().sec);
+        self.dispatch_queues(&mut queues, names, options);
+      },
+      Err(err) => {
+        error!("Queues lock error: {}", err);
+      }
+    }
+  }
+
+  pub fn get_scheduled(&self, name: &str) -> Vec<ScheduledRecord> {
+    match self.queues.lock() {
+      Ok(queues) => queues.get_scheduled(name),
+      Err(err) => {
+        error!("Queues lock error: {}", err);
+        Vec::new()
+      }
+    }
+  }
+
+  pub fn cancel_scheduled(&self, id: u64) -> bool {
+    match self.queues.lock() {
+      Ok(mut queues) => queues.cancel_scheduled(id),
+      Err(err) => {
+        error!("Queues lock error: {}", err);
+        false
+      }
+    }
+  }
+
+  pub fn get_dead(&self, name: &str) -> Vec<DeadRecord> {
+    match self.queues.lock() {
+      Ok(queues) => queues.get_dead(name),
+      Err(err) => {
+        error!("Queues lock error: {}", err);
+        Vec::new()
+      }
+    }
+  }
+
+  // dead tasks go to own queues, number of tasks
+  pub fn requeue_dead(&self, name: &str, id: Option<u64>, options: &ProjectOptions) -> usize {
+    match self.queues.lock() {
+      Ok(mut queues) => {
+        let (count, names) = queues.requeue_dead(name, id, options, time::get_time().sec);
+        self.dispatch_queues(&mut queues, names, options);
+        count
+      },
+      Err(err) => {
+        error!("Queues lock error: {}", err);
+        0
+      }
+    }
+  }
+
+  pub fn purge_dead(&self, name: &str, id: Option<u64>) -> usize {
+    match self.queues.lock() {
+      Ok(mut queues) => queues.purge_dead(name, id),
+      Err(err) => {
+        error!("Queues lock error: {}", err);
+        0
+      }
+    }
+  }
+
+  // server with client data or resumed server
+  pub fn server_connected(&self, cuid: &str, methods: Vec<String>) {
+    match self.servers.lock() {
+      Ok(mut servers) => {
+        // repeated client data replaces methods
+        servers.insert(cuid.to_string(), ServerMethods {
+          methods: methods,
+        });
+      },
+      Err(err) => {
+        error!("Servers lock error: {}", err);
+      }
+    }
+  }
+
+  fn get_methods(&self, cuid: &str) -> Vec<String> {
+    match self.servers.lock() {
+      Ok(servers) => match servers.get(cuid) {
+        Some(server) => server.methods.clone(),
+        None => Vec::new(),
+      },
+      Err(err) => {
+        error!("Servers lock error: {}", err);
+        Vec::new()
+      }
+    }
+  }
+
+  // some connected server can handle tasks of method
+  pub fn has_capable_server(&self, method: &str) -> bool {
+    match self.servers.lock() {
+      Ok(servers) => servers.values().any(
+        |server| server.methods.iter().any(|server_method| server_method == method)),
+      Err(err) => {
+        error!("Servers lock error: {}", err);
+        false
+      }
+    }
+  }
+
+  // server waits one task of queue, task with method waits server with it
+  pub fn consume(&self, queue: &QueueOptions, consumer: String) {
+    let methods = self.get_methods(&consumer);
+    match self.queues.lock() {
+      Ok(mut queues) => {
+        queues.consume(queue, consumer, methods);
+        self.dispatch_queue(&mut queues, queue, time::get_time().sec);
+      },
+      Err(err) => {
+        error!("Queues lock error: {}", err);
+      }
+    }
+  }
+
+  // returned tasks go to waiting servers
+  fn dispatch_queues(&self, queues: &mut QueueRegistry, names: Vec<String>, options: &ProjectOptions) {
+    let now = time::get_time().sec;
+    for name in names {
+      match options.get_queue(&name) {
+        Some(queue) => self.dispatch_queue(queues, queue, now),
+        None => warn!("Tasks of unknown queue '{}'", name),
+      }
+    }
+  }
+
+  // task is done by server
+  pub fn ack(&self, id: u64, consumer: &str) -> bool {
+    match self.queues.lock() {
+      Ok(mut queues) => queues.ack(id, consumer).is_some(),
+      Err(err) => {
+        error!("Queues lock error: {}", err);
+        false
+      }
+    }
+  }
+
+  // task is done by server, Result answer goes to service by cuid
+  pub fn result(&self, id: u64, consumer: &str, data: String, blob: Vec<u8>) -> bool {
+    let task = match self.queues.lock() {
+      Ok(mut queues) => queues.ack(id, consumer),
+      Err(err) => {
+        error!("Queues lock error: {}", err);
+        None
+      }
+    };
+    match task {
+      Some(task) => {
+        let data = TaskResult::new(task.get_id(), task.get_queue(), data);
+        let mut answer = Answer::new();
+        answer.set_target(AnswerTargetEnum::Result.to_u32());
+        answer.set_data(json::encode(&data).unwrap());
+        answer.set_blob(blob);
+        answer.complete(task.get_producer());
+        self.route_producer(vec![answer]);
+        true
+      },
+      None => false,
+    }
+  }
+
+  // task returns to queue at once or goes to dead letters
+  pub fn nack(&self, id: u64, consumer: &str, reason: &str, options: &ProjectOptions) -> bool {
+    match self.queues.lock() {
+      Ok(mut queues) => match queues.nack(id, consumer, reason, options, time::get_time().sec) {
+        Some(Some(name)) => {
+          self.dispatch_queues(&mut queues, vec![name], options);
+          true
+        },
+        Some(None) => true,
+        None => false,
+      },
+      Err(err) => {
+        error!("Queues lock error: {}", err);
+        false
+      }
+    }
+  }
+
+  // tasks without Ack after visibility timeout of queue
+  pub fn requeue_expired(&self, options: &ProjectOptions) {
+    match self.queues.lock() {
+      Ok(mut queues) => {
+        let names = queues.requeue_expired(options, time::get_time().sec);
+        if !names.is_empty() {
+          info!("Tasks of queues {:?} returned after visibility timeout", names);
+        }
+        self.dispatch_queues(&mut queues, names, options);
+      },
+      Err(err) => {
+        error!("Queues lock error: {}", err);
+      }
+    }
+  }
+
+  pub fn take_routed(&self) -> Vec<Answer> {
+    match self.routed.lock() {
+      Ok(mut routed) => routed.drain(..).collect(),
+      Err(err) => {
+        error!("Routed answers lock error: {}", err);
+        Vec::new()
+      }
+    }
+  }
+
+  // false if client is subscribed already
+  pub fn subscribe(&self, topic: &str, cuid: &str) -> bool {
+    match self.topics.lock() {
+      Ok(mut topics) => topics.entry(
+        topic.to_string()).or_insert(HashSet::new()).insert(cuid.to_string()),
+      Err(err) => {
+        error!("Topics lock error: {}", err);
+        false
+      }
+    }
+  }
+
+  // false if client is not subscribed
+  pub fn unsubscribe(&self, topic: &str, cuid: &str) -> bool {
+    match self.topics.lock() {
+      Ok(mut topics) => {
+        let (found, empty) = match topics.get_mut(topic) {
+          Some(subscribers) => (subscribers.remove(cuid), subscribers.is_empty()),
+          None => (false, false),
+        };
+        if empty {
+          topics.remove(topic);
+        }
+        found
+      },
+      Err(err) => {
+        error!("Topics lock error: {}", err);
+        false
+      }
+    }
+  }
+
+  // each subscriber takes Event answer, number of subscribers
+  pub fn publish_topic(&self, topic: &str, data: String, blob: Vec<u8>) -> usize {
+    let subscribers: Vec<String> = match self.topics.lock() {
+      Ok(topics) => match topics.get(topic) {
+        Some(subscribers) => subscribers.iter().cloned().collect(),
+        None => Vec::new(),
+      },
+      Err(err) => {
+        error!("Topics lock error: {}", err);
+        Vec::new()
+      }
+    };
+    if subscribers.is_empty() {
+      return 0;
+    }
+    let event = json::encode(&TopicEvent::new(topic.to_string(), data)).unwrap();
+    match self.routed.lock() {
+      Ok(mut routed) => {
+        for cuid in subscribers.iter() {
+          let mut answer = Answer::new();
+          answer.set_target(AnswerTargetEnum::Event.to_u32());
+          answer.set_data(event.clone());
+          answer.set_blob(blob.clone());
+          answer.complete(cuid.clone());
+          routed.push(answer);
+        }
+        debug!("Event of topic '{}' goes to {} subscribers", topic, subscribers.len());
+        subscribers.len()
+      },
+      Err(err) => {
+        error!("Routed answers lock error: {}", err);
+        0
+      }
+    }
+  }
+
+  // client is closed and it has not resumed yet
+  pub fn is_offline(&self, cuid: &str) -> bool {
+    match self.parked.lock() {
+      Ok(parked) => match parked.get(cuid) {
+        Some(client) => client.offline > 0,
+        None => false,
+      },
+      Err(err) => {
+        error!("Parked answers lock error: {}", err);
+        false
+      }
+    }
+  }
+
+  // answers of closed clients from answer pool, only results and notices wait resume
+  pub fn park(&self, answers: Vec<Answer>) {
+    let results: Vec<Answer> = answers.into_iter().filter(
+      |answer| answer.to_u32() == AnswerTargetEnum::Result.to_u32() ||
+        answer.to_u32() == AnswerTargetEnum::Expired.to_u32()).collect();
+    self.route_producer(results);
+  }
+
+  // parked answers go to client after resume
+  pub fn client_resumed(&self, cuid: &str) {
+    let answers = match self.parked.lock() {
+      Ok(mut parked) => {
+        // close of previous connection can be cleared after resume
+        let (answers, done) = {
+          let client = parked.entry(cuid.to_string()).or_insert(ParkedClient::new());
+          client.offline -= 1;
+          if client.offline > 0 {
+            (Vec::new(), false)
+          } else {
+            (client.answers.drain(..).collect(), client.offline == 0)
+          }
+        };
+        if done {
+          parked.remove(cuid);
+        }
+        answers
+      },
+      Err(err) => {
+        error!("Parked answers lock error: {}", err);
+        Vec::new()
+      }
+    };
+    if !answers.is_empty() {
+      info!("Parked answers go to resumed client {}: {}", cuid, answers.len());
+      self.route_producer(answers);
+    }
+  }
+
+  // answers of clients without resume after ttl of resume token are dropped
+  pub fn drop_parked(&self, options: &ProjectOptions) {
+    let now = time::get_time().sec;
+    match self.parked.lock() {
+      Ok(mut parked) => {
+        let old: Vec<String> = parked.iter().filter(
+          |&(_, client)| client.offline > 0 && client.closed + options.resume_ttl as i64 <= now).map(
+          |(cuid, _)| cuid.clone()).collect();
+        for cuid in old {
+          match parked.remove(&cuid) {
+            Some(ref client) if !client.answers.is_empty() => {
+              warn!("Parked answers of client {} dropped: {}", cuid, client.answers.len());
+            },
+            _ => {}
+          }
+        }
+      },
+      Err(err) => {
+        error!("Parked answers lock error: {}", err);
+      }
+    }
+  }
+
+  // tasks of closed client return to queues, answers for it are parked,
+  // subscriptions and methods of server are removed
+  pub fn client_closed(&self, cuid: &str, options: &ProjectOptions) {
+    match self.topics.lock() {
+      Ok(mut topics) => {
+        for subscribers in topics.values_mut() {
+          subscribers.remove(cuid);
+        }
+        topics.retain(|_, subscribers| !subscribers.is_empty());
+      },
+      Err(err) => {
+        error!("Topics lock error: {}", err);
+      }
+    }
+    let offline = match self.parked.lock() {
+      Ok(mut parked) => {
+        let (offline, done) = {
+          let client = parked.entry(cuid.to_string()).or_insert(ParkedClient::new());
+          client.offline += 1;
+          client.closed = time::get_time().sec;
+          (client.offline > 0, client.offline == 0)
+        };
+        if done {
+          parked.remove(cuid);
+        }
+        offline
+      },
+      Err(err) => {
+        error!("Parked answers lock error: {}", err);
+        true
+      }
+    };
+    // server resumed before close of previous connection keeps methods
+    if offline {
+      match self.servers.lock() {
+        Ok(mut servers) => {
+          servers.remove(cuid);
+        },
+        Err(err) => {
+          error!("Servers lock error: {}", err);
+        }
+      }
+    }
+    match self.queues.lock() {
+      Ok(mut queues) => {
+        let names = queues.remove_consumer(cuid, options, time::get_time().sec);
+        if !names.is_empty() {
+          info!("Tasks of closed client {} returned to queues {:?}", cuid, names);
+        }
+        self.dispatch_queues(&mut queues, names, options);
+      },
+      Err(err) => {
+        error!("Queues lock error: {}", err);
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  extern crate time;
+
+  use options::configuration::ProjectOptions;
+  use state::BrokerState;
+
+  #[test]
+  fn test_auth_ban() {
+    let mut options = ProjectOptions::new();
+    options.auth_max_failures = 3;
+    options.auth_ban_time = 10;
+    options.auth_max_ban_time = 60;
+    let now = time::get_time().sec;
+    let state = BrokerState::new();
+    for _ in 0..2 {
+      state.auth_failed("10.0.0.1", "service1", &options, now);
+    }
+    assert_eq!(state.auth_ban("10.0.0.1", ""), 0);
+    state.auth_failed("10.0.0.1", "service1", &options, now);
+    assert!(state.auth_ban("10.0.0.1", "") > 0);
+    assert!(state.auth_ban("10.0.0.2", "service1") > 0);
+    assert_eq!(state.auth_ban("10.0.0.2", "service2"), 0);
+    // doubled and limited
+    state.auth_failed("10.0.0.1", "", &options, now);
+    assert!(state.auth_ban("10.0.0.1", "") > 10);
+    for _ in 0..40 {
+      state.auth_failed("10.0.0.1", "", &options, now);
+    }
+    assert!(state.auth_ban("10.0.0.1", "") <= 60);
+    assert_eq!(state.get_bans().len(), 2);
+
+    assert_eq!(state.clear_bans("service1"), 1);
+    assert_eq!(state.auth_ban("10.0.0.2", "service1"), 0);
+    state.auth_passed("10.0.0.1", "");
+    assert_eq!(state.auth_ban("10.0.0.1", ""), 0);
+    assert_eq!(state.get_bans().len(), 0);
+  }
+
+  #[test]
+  fn test_auth_failure_window() {
+    let mut options = ProjectOptions::new();
+    options.auth_max_failures = 3;
+    options.auth_failure_window = 60;
+    let now = time::get_time().sec;
+    let state = BrokerState::new();
+    // failures in different windows don't make ban
+    for age in [300, 200, 100, 0].iter() {
+      state.auth_failed("10.0.0.1", "service1", &options, now - age);
+    }
+    assert_eq!(state.auth_ban("10.0.0.1", "service1"), 0);
+    state.auth_failed("10.0.0.1", "service1", &options, now);
+    state.auth_failed("10.0.0.1", "service1", &options, now);
+    assert!(state.auth_ban("10.0.0.1", "service1") > 0);
+
+    // old failures without ban are dropped, bans stay
+    state.auth_failed("10.0.0.2", "service2", &options, now - 100);
+    assert_eq!(state.auth_failures.lock().unwrap().len(), 4);
+    state.drop_auth_failures(&options);
+    assert_eq!(state.auth_failures.lock().unwrap().len(), 2);
+    assert!(state.auth_ban("10.0.0.1", "service1") > 0);
+  }
+}
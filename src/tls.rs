@@ -0,0 +1,254 @@
+use openssl::error::ErrorStack;
+use openssl::nid::Nid;
+use openssl::ssl::{SslAcceptor, SslFiletype, SslMethod, SslStream, SslVerifyMode};
+use openssl::x509::{X509Name, X509VerifyResult};
+use options::configuration::ProjectOptions;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::time::Duration;
+
+// === data ===
+// socket of client connection, plain or encrypted
+pub enum ClientStream {
+  Plain(TcpStream),
+  Tls(SslStream<TcpStream>),
+}
+
+// === impl ===
+impl ClientStream {
+  fn get_tcp(&self) -> &TcpStream {
+    match *self {
+      ClientStream::Plain(ref stream) => stream,
+      ClientStream::Tls(ref stream) => stream.get_ref(),
+    }
+  }
+
+  pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+    self.get_tcp().peer_addr()
+  }
+
+  pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+    self.get_tcp().set_read_timeout(timeout)
+  }
+
+  pub fn is_tls(&self) -> bool {
+    match *self {
+      ClientStream::Plain(_) => false,
+      ClientStream::Tls(_) => true,
+    }
+  }
+
+  // common name of client certificate which is checked by CA
+  pub fn peer_identity(&self) -> Option<String> {
+    let ssl = match *self {
+      ClientStream::Plain(_) => return None,
+      ClientStream::Tls(ref stream) => stream.ssl(),
+    };
+    if ssl.verify_result() != X509VerifyResult::OK {
+      return None;
+    }
+    let certificate = match ssl.peer_certificate() {
+      Some(certificate) => certificate,
+      None => return None,
+    };
+    let common_name = match certificate.subject_name().entries_by_nid(Nid::COMMONNAME).next() {
+      Some(entry) => entry.data().as_utf8(),
+      None => return None,
+    };
+    match common_name {
+      Ok(name) => Some(name.to_string()),
+      Err(err) => {
+        warn!("Client certificate name error: {}", err);
+        None
+      }
+    }
+  }
+}
+
+// === impl trait ===
+impl Read for ClientStream {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    match *self {
+      ClientStream::Plain(ref mut stream) => stream.read(buf),
+      ClientStream::Tls(ref mut stream) => stream.read(buf),
+    }
+  }
+}
+
+impl Write for ClientStream {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    match *self {
+      ClientStream::Plain(ref mut stream) => stream.write(buf),
+      ClientStream::Tls(ref mut stream) => stream.write(buf),
+    }
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    match *self {
+      ClientStream::Plain(ref mut stream) => stream.flush(),
+      ClientStream::Tls(ref mut stream) => stream.flush(),
+    }
+  }
+}
+
+// === iface ===
+// acceptor from certificate (pem chain) and private key (pem) of server,
+// client certificate is optional, clients without it use secret
+pub fn create_acceptor(options: &ProjectOptions) -> Result<SslAcceptor, ErrorStack> {
+  let mut builder = try!(SslAcceptor::mozilla_intermediate(SslMethod::tls()));
+  try!(builder.set_private_key_file(&options.tls_key, SslFiletype::PEM));
+  try!(builder.set_certificate_chain_file(&options.tls_cert));
+  try!(builder.check_private_key());
+  if !options.tls_ca.is_empty() {
+    try!(builder.set_ca_file(&options.tls_ca));
+    builder.set_client_ca_list(try!(X509Name::load_client_ca_file(&options.tls_ca)));
+    builder.set_verify(SslVerifyMode::PEER);
+  }
+  Ok(builder.build())
+}
+
+// handshake with client, timeout protects thread from silent clients
+pub fn accept_client(
+    acceptor: &SslAcceptor, stream: TcpStream, timeout: Duration) -> Option<ClientStream> {
+  match stream.set_read_timeout(Some(timeout)) {
+    Ok(_) => {},
+    Err(err) => {
+      warn!("Can't set handshake timeout: {}", err);
+      return None;
+    }
+  }
+  match acceptor.accept(stream) {
+    Ok(tls_stream) => Some(ClientStream::Tls(tls_stream)),
+    Err(err) => {
+      warn!("TLS handshake error: {}", err);
+      None
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  extern crate rand;
+  use openssl::asn1::Asn1Time;
+  use openssl::hash::MessageDigest;
+  use openssl::pkey::{PKey, Private};
+  use openssl::rsa::Rsa;
+  use openssl::ssl::{SslConnector, SslMethod};
+  use openssl::x509::{X509, X509NameBuilder};
+  use openssl::x509::extension::BasicConstraints;
+  use options::configuration::ProjectOptions;
+  use rand::Rng;
+  use std::env;
+  use std::fs::File;
+  use std::io::prelude::*;
+  use std::net::{TcpListener, TcpStream};
+  use std::thread;
+  use std::time::Duration;
+  use tls::{ClientStream, create_acceptor, accept_client};
+
+  // certificate signed by issuer or self-signed
+  fn create_certificate(
+      common_name: &str, issuer: Option<(&X509, &PKey<Private>)>) -> (X509, PKey<Private>) {
+    let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+    let mut name = X509NameBuilder::new().unwrap();
+    name.append_entry_by_text("CN", common_name).unwrap();
+    let name = name.build();
+    let mut builder = X509::builder().unwrap();
+    builder.set_version(2).unwrap();
+    builder.set_subject_name(&name).unwrap();
+    builder.set_pubkey(&key).unwrap();
+    builder.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+    builder.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+    match issuer {
+      Some((issuer_certificate, issuer_key)) => {
+        builder.set_issuer_name(issuer_certificate.subject_name()).unwrap();
+        builder.sign(issuer_key, MessageDigest::sha256()).unwrap();
+      },
+      None => {
+        // self-signed is root, it can sign certificates of clients
+        builder.append_extension(BasicConstraints::new().critical().ca().build().unwrap()).unwrap();
+        builder.set_issuer_name(&name).unwrap();
+        builder.sign(&key, MessageDigest::sha256()).unwrap();
+      }
+    }
+    (builder.build(), key)
+  }
+
+  fn save_tmp_file(content: &[u8], extension: &str) -> String {
+    let mut rng = rand::thread_rng();
+    let mut tmp_path = env::temp_dir();
+    tmp_path.push(format!("00-0{}.{}", rng.gen::<u32>(), extension));
+    File::create(&tmp_path).unwrap().write_all(content).unwrap();
+    tmp_path.to_str().unwrap().to_string()
+  }
+
+  fn server_options() -> (ProjectOptions, X509) {
+    let mut options = ProjectOptions::new();
+    let (certificate, key) = create_certificate("localhost", None);
+    options.tls_cert = save_tmp_file(&certificate.to_pem().unwrap(), "crt");
+    options.tls_key = save_tmp_file(&key.private_key_to_pem_pkcs8().unwrap(), "key");
+    (options, certificate)
+  }
+
+  fn accept_one(options: &ProjectOptions, listener: TcpListener) -> ClientStream {
+    let acceptor = create_acceptor(options).unwrap();
+    let (stream, _) = listener.accept().unwrap();
+    accept_client(&acceptor, stream, Duration::from_secs(5)).unwrap()
+  }
+
+  #[test]
+  fn test_tls_stream() {
+    let (options, certificate) = server_options();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let client = thread::spawn(move || {
+      let mut builder = SslConnector::builder(SslMethod::tls()).unwrap();
+      builder.cert_store_mut().add_cert(certificate).unwrap();
+      let connector = builder.build();
+      let stream = TcpStream::connect(addr).unwrap();
+      let mut stream = connector.connect("localhost", stream).unwrap();
+      stream.write_all(b"ping").unwrap();
+      let mut answer = [0; 4];
+      stream.read_exact(&mut answer).unwrap();
+      answer
+    });
+
+    let mut stream = accept_one(&options, listener);
+    assert!(stream.is_tls());
+    assert!(stream.peer_identity().is_none());
+    let mut data = [0; 4];
+    stream.read_exact(&mut data).unwrap();
+    assert_eq!(&data, b"ping");
+    stream.write_all(b"pong").unwrap();
+    assert_eq!(&client.join().unwrap(), b"pong");
+  }
+
+  #[test]
+  fn test_client_certificate() {
+    let (mut options, certificate) = server_options();
+    let (ca_certificate, ca_key) = create_certificate("roomb ca", None);
+    let (client_certificate, client_key) = create_certificate(
+      "service1", Some((&ca_certificate, &ca_key)));
+    options.tls_ca = save_tmp_file(&ca_certificate.to_pem().unwrap(), "crt");
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let client = thread::spawn(move || {
+      let mut builder = SslConnector::builder(SslMethod::tls()).unwrap();
+      builder.cert_store_mut().add_cert(certificate).unwrap();
+      builder.set_certificate(&client_certificate).unwrap();
+      builder.set_private_key(&client_key).unwrap();
+      let connector = builder.build();
+      let stream = TcpStream::connect(addr).unwrap();
+      let mut stream = connector.connect("localhost", stream).unwrap();
+      stream.write_all(b"ping").unwrap();
+    });
+
+    let mut stream = accept_one(&options, listener);
+    let mut data = [0; 4];
+    stream.read_exact(&mut data).unwrap();
+    assert_eq!(stream.peer_identity(), Some("service1".to_string()));
+    client.join().unwrap();
+  }
+}
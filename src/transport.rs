@@ -1,15 +1,23 @@
 extern crate time;
+use codec::{
+  FramingEnum, EncodingEnum, MsgPackValue, encode_frame, encode_msgpack_record,
+  decode_msgpack_record};
 use common::helpers::{Description, get_random_digit_string};
-use handler::exec::get_answer_method;
+use consts::common::LEGACY_PROTOCOL_VERSION;
+use handler::exec::get_allowed_answer_method;
 use options::configuration::ProjectOptions;
 use std::clone::Clone;
 use std::cmp::PartialEq;
-use std::net::{SocketAddr, TcpStream};
+use std::net::SocketAddr;
 use std::io::Write;
 use protocol::{
   CommandTargetEnum, TargetAsDigit, LookAsTargetCommandEnum,
   LookAsTargetAnswerEnum, ClientGroupEnum};
+use rustc_serialize::base64::{STANDARD, ToBase64};
 use rustc_serialize::json;
+use state::BrokerState;
+use crypto::sha2::Sha256;
+use crypto::digest::Digest;
 
 // === trait ===
 
@@ -44,17 +52,21 @@ pub trait CuidOwner {
 }
 
 pub trait AnswerWriter {
-  fn write(&self, stream: &mut TcpStream) -> bool;
+  fn write(&self, stream: &mut Write, framing: &FramingEnum, encoding: &EncodingEnum) -> bool;
 }
 
 
 pub trait CommandDataCreator {
-  fn setup(&mut self, src: &JsonBufferCommand);
-  fn append(&mut self, src: &JsonBufferCommand);
+  fn setup(&mut self, src: &JsonBufferCommand, max_size: usize) -> Result<(), PartError>;
+  fn append(&mut self, src: &JsonBufferCommand, max_size: usize) -> Result<(), PartError>;
 }
 
 pub trait CommandCreationAnswer {
-  fn get_answer(&self, options: &ProjectOptions, connection_data: &mut ClientConnectionData) -> Answer;
+  fn get_answer(
+    &self,
+    options: &ProjectOptions,
+    connection_data: &mut ClientConnectionData,
+    state: &BrokerState) -> Answer;
 }
 
 // === struct ===
@@ -64,6 +76,13 @@ pub struct JsonBufferCommand {
   part: bool,
   cid: String,
   data: String,
+  rid: Option<String>,
+  // multi-part command: index of part, size of all data, sha256 (hex) of all data
+  seq: Option<u32>,
+  total: Option<u32>,
+  checksum: Option<String>,
+  // binary payload, bin field of MessagePack record
+  blob: Option<Vec<u8>>,
 }
 
 #[derive(RustcDecodable, RustcEncodable)]
@@ -71,6 +90,17 @@ pub struct JsonBufferAnswer {
   target: u32,
   cid: String,
   data: String,
+  rid: Option<String>,
+}
+
+// answer with binary payload for json client
+#[derive(RustcDecodable, RustcEncodable)]
+pub struct JsonBlobAnswer {
+  target: u32,
+  cid: String,
+  data: String,
+  rid: Option<String>,
+  blob: String,
 }
 
 pub struct ClientId {
@@ -83,6 +113,12 @@ pub struct Answer {
   busy: bool,
   sent: bool,
   data: String,
+  // binary payload, it goes to client in bin field of MessagePack record
+  blob: Vec<u8>,
+  // request id from command
+  rid: Option<String>,
+  // connection data after command, none if answer is not for own command
+  connection: Option<ClientConnectionData>,
 }
 
 pub struct Command {
@@ -92,12 +128,41 @@ pub struct Command {
   part: bool,
   busy: bool,
   data: String,
+  rid: Option<String>,
+  connection: ClientConnectionData,
+  // state of multi-part command
+  seq: u32,
+  sequenced: bool,
+  total: Option<usize>,
+  checksum: Option<String>,
+  // binary payload of all parts
+  blob: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub enum PartError {
+  OutOfOrder(u32, Option<u32>),
+  TooLarge(usize),
+  WrongSize(usize, usize),
+  WrongChecksum,
 }
 
 pub struct ClientConnectionData {
   tmp: Vec<u8>,
   cuid: String,
+  // ip of client
+  address: String,
+  // identity from SigIn and group of it after auth
+  identity: String,
   group: u32,
+  // client passed Auth, identity is not changed after it
+  auth: bool,
+  encoding: EncodingEnum,
+  version: u32,
+  // agreed heartbeat interval (seconds), zero - default from options
+  heartbeat: u32,
+  // binary payload of command in handler
+  blob: Vec<u8>,
 }
 
 // === impl ===
@@ -109,11 +174,140 @@ impl ClientId {
   }
 }
 
+impl JsonBufferCommand {
+  pub fn from_msgpack(body: &[u8]) -> Option<JsonBufferCommand> {
+    let fields = match decode_msgpack_record(body) {
+      Some(fields) => fields,
+      None => return None,
+    };
+    let mut target: Option<u32> = None;
+    let mut part: Option<bool> = None;
+    let mut cid: Option<String> = None;
+    let mut data: Option<String> = None;
+    let mut rid: Option<String> = None;
+    let mut seq: Option<u32> = None;
+    let mut total: Option<u32> = None;
+    let mut checksum: Option<String> = None;
+    let mut blob: Option<Vec<u8>> = None;
+    for (name, value) in fields {
+      match (name.as_ref(), value) {
+        ("target", MsgPackValue::Uint(value)) => target = Some(value as u32),
+        ("part", MsgPackValue::Bool(value)) => part = Some(value),
+        ("cid", MsgPackValue::Str(value)) => cid = Some(value),
+        ("data", MsgPackValue::Str(value)) => data = Some(value),
+        ("blob", MsgPackValue::Bin(value)) => blob = Some(value),
+        ("blob", MsgPackValue::Nil) => blob = None,
+        ("rid", MsgPackValue::Str(value)) => rid = Some(value),
+        ("rid", MsgPackValue::Nil) => rid = None,
+        ("seq", MsgPackValue::Uint(value)) => seq = Some(value as u32),
+        ("seq", MsgPackValue::Nil) => seq = None,
+        ("total", MsgPackValue::Uint(value)) => total = Some(value as u32),
+        ("total", MsgPackValue::Nil) => total = None,
+        ("checksum", MsgPackValue::Str(value)) => checksum = Some(value),
+        ("checksum", MsgPackValue::Nil) => checksum = None,
+        _ => return None,
+      }
+    }
+    match (target, part, cid, data) {
+      (Some(target), Some(part), Some(cid), Some(data)) => {
+        Some(JsonBufferCommand {
+          target: target,
+          part: part,
+          cid: cid,
+          data: data,
+          rid: rid,
+          seq: seq,
+          total: total,
+          checksum: checksum,
+          blob: blob,
+        })
+      },
+      _ => None,
+    }
+  }
+}
+
+impl JsonBufferAnswer {
+  // binary payload goes in bin field if answer has it
+  pub fn to_msgpack(&self, blob: &Vec<u8>) -> Vec<u8> {
+    let mut fields = vec![
+      ("target", MsgPackValue::Uint(self.target as u64)),
+      ("cid", MsgPackValue::Str(self.cid.clone())),
+      ("data", MsgPackValue::Str(self.data.clone())),
+      ("rid", match self.rid {
+        Some(ref rid) => MsgPackValue::Str(rid.clone()),
+        None => MsgPackValue::Nil,
+      })];
+    if !blob.is_empty() {
+      fields.push(("blob", MsgPackValue::Bin(blob.clone())));
+    }
+    encode_msgpack_record(&fields)
+  }
+}
+
 impl Command {
   pub fn is_full(&self) -> bool {
     self.full
   }
 
+  // command changes connection data, so it runs
+  // only when connection has no other commands in workers
+  pub fn is_exclusive(&self) -> bool {
+    self.target == CommandTargetEnum::SigIn.to_u32() ||
+    self.target == CommandTargetEnum::Auth.to_u32() ||
+    self.target == CommandTargetEnum::ClientData.to_u32() ||
+    self.target == CommandTargetEnum::Quit.to_u32()
+  }
+
+  pub fn set_connection(&mut self, connection_data: &ClientConnectionData) {
+    self.connection = connection_data.clone();
+  }
+
+  pub fn get_connection(&self) -> ClientConnectionData {
+    self.connection.clone()
+  }
+
+  fn check_part(&mut self, src: &JsonBufferCommand, max_size: usize) -> Result<(), PartError> {
+    // parts with index go one by one, parts of old clients have no index
+    if src.seq != if self.sequenced { Some(self.seq) } else { None } {
+      return Err(PartError::OutOfOrder(self.seq, src.seq));
+    }
+    self.seq += 1;
+    // binary payload is in size and checksum of command
+    let size = self.data.len() + self.blob.len();
+    let limit = match self.total {
+      Some(total) if total > max_size => return Err(PartError::TooLarge(total)),
+      Some(total) => total,
+      None => max_size,
+    };
+    if size > limit {
+      return match self.total {
+        Some(total) => Err(PartError::WrongSize(total, size)),
+        None => Err(PartError::TooLarge(size)),
+      };
+    }
+    if self.full {
+      match self.total {
+        Some(total) if total != size => {
+          return Err(PartError::WrongSize(total, size));
+        },
+        _ => {}
+      }
+      match self.checksum {
+        Some(ref checksum) => {
+          let mut hasher = Sha256::new();
+          hasher.input_str(&self.data);
+          hasher.input(&self.blob);
+          if hasher.result_str() != checksum.to_lowercase() {
+            return Err(PartError::WrongChecksum);
+          }
+        },
+        None => {}
+      }
+    }
+    Ok(())
+  }
+
   pub fn need_auth(&self) -> bool {
     !(
       self.target == CommandTargetEnum::SigIn.to_u32() ||
@@ -140,6 +334,27 @@ impl Answer {
   pub fn set_data(&mut self, data: String) {
     self.data = data;
   }
+
+  pub fn set_blob(&mut self, blob: Vec<u8>) {
+    self.blob = blob;
+  }
+
+  pub fn set_target(&mut self, target: u32) {
+    self.target = target;
+  }
+
+  // cell of answer pool is free
+  pub fn is_empty(&self) -> bool {
+    self.cuid.is_none()
+  }
+
+  pub fn set_connection(&mut self, connection_data: &ClientConnectionData) {
+    self.connection = Some(connection_data.clone());
+  }
+
+  pub fn get_connection(&self) -> Option<ClientConnectionData> {
+    self.connection.clone()
+  }
 }
 
 impl ClientConnectionData {
@@ -147,7 +362,14 @@ impl ClientConnectionData {
     ClientConnectionData {
       cuid: String::new(),
       tmp: Vec::new(),
+      address: String::new(),
+      identity: String::new(),
       group: ClientGroupEnum::Service.to_u32(),
+      auth: false,
+      encoding: EncodingEnum::Json,
+      version: LEGACY_PROTOCOL_VERSION,
+      heartbeat: 0,
+      blob: Vec::new(),
     }  
   }
 
@@ -180,6 +402,70 @@ impl ClientConnectionData {
     String::from_utf8(self.tmp.clone()).unwrap()
   }
 
+  pub fn set_encoding(&mut self, encoding: EncodingEnum) {
+    self.encoding = encoding;
+  }
+
+  pub fn get_encoding(&self) -> EncodingEnum {
+    self.encoding
+  }
+
+  pub fn set_version(&mut self, version: u32) {
+    self.version = version;
+  }
+
+  pub fn get_version(&self) -> u32 {
+    self.version
+  }
+
+  pub fn set_heartbeat(&mut self, interval: u32) {
+    self.heartbeat = interval;
+  }
+
+  pub fn get_heartbeat(&self) -> u32 {
+    self.heartbeat
+  }
+
+  pub fn set_blob(&mut self, blob: Vec<u8>) {
+    self.blob = blob;
+  }
+
+  pub fn get_blob(&self) -> Vec<u8> {
+    self.blob.clone()
+  }
+
+  pub fn set_address(&mut self, address: String) {
+    self.address = address;
+  }
+
+  pub fn get_address(&self) -> String {
+    self.address.clone()
+  }
+
+  pub fn set_identity(&mut self, identity: String) {
+    self.identity = identity;
+  }
+
+  pub fn get_identity(&self) -> String {
+    self.identity.clone()
+  }
+
+  pub fn set_group(&mut self, group: u32) {
+    self.group = group;
+  }
+
+  pub fn get_group(&self) -> u32 {
+    self.group
+  }
+
+  pub fn set_auth(&mut self, auth: bool) {
+    self.auth = auth;
+  }
+
+  pub fn is_auth(&self) -> bool {
+    self.auth
+  }
+
   pub fn clear(&mut self) {
     self.tmp.clear();
     //self.cuid.clear();
@@ -188,6 +474,14 @@ impl ClientConnectionData {
   pub fn copy(&mut self, src: &Self) {
     self.tmp = src.tmp.clone();
     self.cuid = src.cuid.clone();
+    self.address = src.address.clone();
+    self.identity = src.identity.clone();
+    self.group = src.group;
+    self.auth = src.auth;
+    self.encoding = src.encoding;
+    self.version = src.version;
+    self.heartbeat = src.heartbeat;
+    self.blob = src.blob.clone();
   }
 }
 // === impl trait ===
@@ -222,18 +516,30 @@ impl LookAsTargetCommandEnum for Command {
 }
 
 impl CommandDataCreator for Command {
-  fn setup(&mut self, src: &JsonBufferCommand) {
+  fn setup(&mut self, src: &JsonBufferCommand, max_size: usize) -> Result<(), PartError> {
     self.busy = false;
     self.full = !src.part;
     self.part = src.part;
     self.data.push_str(&src.data);
     self.target = src.target;
+    self.rid = src.rid.clone();
+    self.seq = 0;
+    self.sequenced = src.seq.is_some();
+    self.total = src.total.map(|total| total as usize);
+    self.checksum = src.checksum.clone();
+    self.blob = src.blob.clone().unwrap_or(Vec::new());
+    self.check_part(src, max_size)
   }
 
-  fn append(&mut self, src: &JsonBufferCommand) {
+  fn append(&mut self, src: &JsonBufferCommand, max_size: usize) -> Result<(), PartError> {
     self.full = !src.part;
     self.part = src.part;
     self.data.push_str(&src.data);
+    match src.blob {
+      Some(ref blob) => self.blob.extend_from_slice(blob),
+      None => {}
+    }
+    self.check_part(src, max_size)
   }
 }
 
@@ -326,6 +632,13 @@ impl TransportConstructor for Command {
       part: false,
       busy: false,
       data: String::new(),
+      rid: None,
+      connection: ClientConnectionData::new(),
+      seq: 0,
+      sequenced: false,
+      total: None,
+      checksum: None,
+      blob: Vec::new(),
     }
   }
 
@@ -336,6 +649,13 @@ impl TransportConstructor for Command {
     self.target = CommandTargetEnum::Unknown.to_u32();
     self.cuid = None;
     self.data.clear();
+    self.rid = None;
+    self.connection = ClientConnectionData::new();
+    self.seq = 0;
+    self.sequenced = false;
+    self.total = None;
+    self.checksum = None;
+    self.blob.clear();
   }
 
   fn is_new(&self) -> bool {
@@ -349,6 +669,9 @@ impl TransportConstructor for Command {
     self.target = src.target as u32;
     self.data = src.data.clone();
     self.cuid = src.cuid.clone();
+    self.rid = src.rid.clone();
+    self.connection = src.connection.clone();
+    self.blob = src.blob.clone();
   }
 }
 
@@ -360,6 +683,9 @@ impl TransportConstructor for Answer {
       busy: false,
       sent: false,
       data: String::new(),
+      blob: Vec::new(),
+      rid: None,
+      connection: None,
     }
   }
 
@@ -368,7 +694,10 @@ impl TransportConstructor for Answer {
     self.busy = false;
     self.sent = false;
     self.data.clear();
+    self.blob.clear();
     self.cuid = None;
+    self.rid = None;
+    self.connection = None;
   }
 
   fn is_new(&self) -> bool {
@@ -385,7 +714,14 @@ impl Clone for ClientConnectionData {
     ClientConnectionData {
       cuid: self.cuid.clone(),
       tmp: self.tmp.clone(),
+      address: self.address.clone(),
+      identity: self.identity.clone(),
       group: self.group.clone(),
+      auth: self.auth.clone(),
+      encoding: self.encoding.clone(),
+      version: self.version.clone(),
+      heartbeat: self.heartbeat.clone(),
+      blob: self.blob.clone(),
     }
   }
 }
@@ -398,12 +734,15 @@ impl Clone for Answer {
       busy: self.busy.clone(),
       sent: self.sent.clone(),
       data: self.data.clone(),
+      blob: self.blob.clone(),
+      rid: self.rid.clone(),
+      connection: self.connection.clone(),
     }
   }
 }
 
 impl AnswerWriter for Answer {
-  fn write(&self, stream: &mut TcpStream) -> bool {
+  fn write(&self, stream: &mut Write, framing: &FramingEnum, encoding: &EncodingEnum) -> bool {
     // return done - true
     match self.cuid {
       Some(ref cid) => {
@@ -414,8 +753,24 @@ impl AnswerWriter for Answer {
             None => String::new(),
           },
           target: self.target,
+          rid: self.rid.clone(),
+        };
+        let body = match *encoding {
+          EncodingEnum::Json if self.blob.is_empty() => {
+            json::encode(&new_data).unwrap().into_bytes()
+          },
+          // json client takes binary payload as base64
+          EncodingEnum::Json => json::encode(&JsonBlobAnswer {
+            target: new_data.target,
+            cid: new_data.cid,
+            data: new_data.data,
+            rid: new_data.rid,
+            blob: self.blob.to_base64(STANDARD),
+          }).unwrap().into_bytes(),
+          EncodingEnum::MsgPack => new_data.to_msgpack(&self.blob),
         };
-        match stream.write_all(json::encode(&new_data).unwrap().as_bytes()) {
+        let frame = encode_frame(&body, framing);
+        match stream.write_all(&frame) {
           Ok(_) => true,
           Err(err) => {
             error!("Error write to client {}: {}", cid, err);
@@ -447,6 +802,9 @@ impl TransportCopy for Answer {
     self.sent = src.sent;
     self.target = src.target;
     self.data = src.data.clone();
+    self.blob = src.blob.clone();
+    self.rid = src.rid.clone();
+    self.connection = src.connection.clone();
   }
 }
 
@@ -517,6 +875,24 @@ impl Clone for Command {
       part: self.part.clone(),
       busy: self.busy.clone(),
       data: self.data.clone(),
+      rid: self.rid.clone(),
+      connection: self.connection.clone(),
+      seq: self.seq.clone(),
+      sequenced: self.sequenced.clone(),
+      total: self.total.clone(),
+      checksum: self.checksum.clone(),
+      blob: self.blob.clone(),
+    }
+  }
+}
+
+impl Description for PartError {
+  fn description(&self) -> String {
+    match *self {
+      PartError::OutOfOrder(expected, seq) => format!("part {:?} income, {} expected", seq, expected),
+      PartError::TooLarge(size) => format!("command size {} is too large", size),
+      PartError::WrongSize(total, size) => format!("command size {} but {} declared", size, total),
+      PartError::WrongChecksum => "command checksum is wrong".to_string(),
     }
   }
 }
@@ -544,8 +920,16 @@ impl Description for Answer {
 }
 
 impl CommandCreationAnswer for Command {
-  fn get_answer(&self, options: &ProjectOptions, connection_data: &mut ClientConnectionData) -> Answer {
-    let (answer_target, answer_data) = get_answer_method(self.as_target_enum())(&self.data, connection_data, &options);
+  fn get_answer(
+      &self,
+      options: &ProjectOptions,
+      connection_data: &mut ClientConnectionData,
+      state: &BrokerState) -> Answer {
+    let method = get_allowed_answer_method(self.target, connection_data, &options);
+    // binary payload is for handler of this command only
+    connection_data.set_blob(self.blob.clone());
+    let (answer_target, answer_data) = method(&self.data, connection_data, &options, state);
+    connection_data.set_blob(Vec::new());
     Answer {
       cuid: match self.cuid {
         Some(ref cuid) => Some(cuid.clone()),
@@ -556,7 +940,10 @@ impl CommandCreationAnswer for Command {
       busy: false,
       sent: false,
       data: answer_data,
+      blob: Vec::new(),
       target: answer_target,
+      rid: self.rid.clone(),
+      connection: None,
     }
   }
 }
@@ -578,6 +965,13 @@ impl CreateTestRecord<Command> for Command {
           part: false,
           busy: false,
           data: String::new(),
+          rid: None,
+          connection: ClientConnectionData::new(),
+          seq: 0,
+          sequenced: false,
+          total: None,
+          checksum: None,
+          blob: Vec::new(),
         }
       },
       _ => {
@@ -588,6 +982,13 @@ impl CreateTestRecord<Command> for Command {
           part: false,
           busy: false,
           data: String::new(),
+          rid: None,
+          connection: ClientConnectionData::new(),
+          seq: 0,
+          sequenced: false,
+          total: None,
+          checksum: None,
+          blob: Vec::new(),
         }
       }
     }
@@ -615,6 +1016,11 @@ impl CreateTestRecord<JsonBufferCommand> for JsonBufferCommand {
           part: false,
           cid: "test_1".to_string(),
           data: "test_1".to_string(),
+          rid: Some("1".to_string()),
+          seq: None,
+          total: None,
+          checksum: None,
+          blob: None,
         }
       },
       2 => {
@@ -623,6 +1029,11 @@ impl CreateTestRecord<JsonBufferCommand> for JsonBufferCommand {
           part: true,
           cid: "test_0".to_string(),
           data: "test_0".to_string(),
+          rid: None,
+          seq: None,
+          total: None,
+          checksum: None,
+          blob: None,
         }
       },
       _ => {
@@ -631,6 +1042,11 @@ impl CreateTestRecord<JsonBufferCommand> for JsonBufferCommand {
           part: true,
           cid: "test_0".to_string(),
           data: "test_0".to_string(),
+          rid: None,
+          seq: None,
+          total: None,
+          checksum: None,
+          blob: None,
         }
       }
     }